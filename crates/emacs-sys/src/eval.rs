@@ -1,6 +1,10 @@
 //! Generic Lisp eval functions
 
+use std::any::Any;
+
 use crate::bindings::xsignal;
+use crate::globals::Qerror;
+use crate::globals::Qnil;
 use crate::lisp::LispObject;
 
 /// Signal an error.  Args are ERROR-SYMBOL and associated DATA. This
@@ -27,3 +31,21 @@ pub fn signal_rust(error_symbol: LispObject, data: LispObject) -> ! {
         unreachable!();
     }
 }
+
+/// Signal an `error' for a panic caught by a function called from C,
+/// which it must not unwind out of. PAYLOAD is the payload of the panic
+/// as returned by `catch_unwind'.
+pub fn signal_panic(payload: Box<dyn Any + Send>) -> ! {
+    let message = if let Some(s) = payload.downcast_ref::<&str>() {
+        format!("Rust panic: {}", s)
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        format!("Rust panic: {}", s)
+    } else {
+        "Rust panic".to_string()
+    };
+    // Signaling does not return, so nothing may be left to drop.
+    drop(payload);
+    let data = LispObject::cons(LispObject::from(message.as_str()), Qnil);
+    drop(message);
+    signal_rust(Qerror, data)
+}
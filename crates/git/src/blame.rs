@@ -0,0 +1,191 @@
+use std::path::Path;
+
+use git2::BlameOptions;
+use git2::Repository;
+
+use lisp_async::fns::rust_worker;
+use lisp_async::fns::to_owned_userdata;
+use lisp_async::fns::EmacsPipe;
+use lisp_async::fns::UserData;
+use lisp_macros::lisp_fn;
+
+use emacs_sys::bindings::make_int;
+use emacs_sys::bindings::Ffuncall;
+use emacs_sys::bindings::Fprocess_get;
+use emacs_sys::bindings::Fprocess_put;
+use emacs_sys::globals::QCauthor;
+use emacs_sys::globals::QCboundary;
+use emacs_sys::globals::QCcallback;
+use emacs_sys::globals::QCcount;
+use emacs_sys::globals::QCemail;
+use emacs_sys::globals::QCid;
+use emacs_sys::globals::QCorig_path;
+use emacs_sys::globals::QCorig_start;
+use emacs_sys::globals::QCstart;
+use emacs_sys::globals::QCtime;
+use emacs_sys::globals::Qdone;
+use emacs_sys::globals::Qerror;
+use emacs_sys::globals::Qgit__blame_handler;
+use emacs_sys::globals::Qnil;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::convert::bytes_to_lisp;
+use crate::convert::list_from_vec;
+use crate::error::error_data;
+use crate::error::signal_git_error;
+use crate::repository::relative_path;
+use crate::repository::with_repository;
+
+/// A blame hunk copied out of libgit2, so that it can be computed on a
+/// worker thread and handed over to the Lisp thread.
+struct BlameEntry {
+    id: String,
+    author: Vec<u8>,
+    email: Vec<u8>,
+    time: i64,
+    start: usize,
+    orig_start: usize,
+    count: usize,
+    orig_path: Option<Vec<u8>>,
+    boundary: bool,
+}
+
+type BlameResult = Result<Vec<BlameEntry>, git2::Error>;
+
+fn blame(git_dir: &Path, path: &Path, newest: Option<&str>) -> BlameResult {
+    let repo = Repository::open(git_dir)?;
+    let mut opts = BlameOptions::new();
+    if let Some(rev) = newest {
+        opts.newest_commit(repo.revparse_single(rev)?.peel_to_commit()?.id());
+    }
+
+    let blame = repo.blame_file(path, Some(&mut opts))?;
+    Ok(blame
+        .iter()
+        .map(|hunk| {
+            let sig = hunk.final_signature();
+            BlameEntry {
+                id: hunk.final_commit_id().to_string(),
+                author: sig.name_bytes().to_vec(),
+                email: sig.email_bytes().to_vec(),
+                time: sig.when().seconds(),
+                start: hunk.final_start_line(),
+                orig_start: hunk.orig_start_line(),
+                count: hunk.lines_in_hunk(),
+                orig_path: hunk
+                    .path()
+                    .map(|p| p.to_string_lossy().into_owned().into_bytes()),
+                boundary: hunk.is_boundary(),
+            }
+        })
+        .collect())
+}
+
+fn entry_to_lisp(entry: &BlameEntry) -> LispObject {
+    list!(
+        QCid,
+        LispObject::from(entry.id.as_str()),
+        QCauthor,
+        bytes_to_lisp(&entry.author),
+        QCemail,
+        bytes_to_lisp(&entry.email),
+        QCtime,
+        unsafe { make_int(entry.time) },
+        QCstart,
+        LispObject::from(entry.start),
+        QCorig_start,
+        LispObject::from(entry.orig_start),
+        QCcount,
+        LispObject::from(entry.count),
+        QCorig_path,
+        entry.orig_path.as_deref().map_or(Qnil, bytes_to_lisp),
+        QCboundary,
+        LispObject::from(entry.boundary)
+    )
+}
+
+/// Blame the file PATH in the repository containing REPO on a worker
+/// thread, and call CALLBACK with the result once it is ready.
+///
+/// CALLBACK is called with two arguments, a status and data, as for
+/// `git-fetch'. The status is `done' with a list of hunks, or `error'
+/// with the error blaming failed with, as in `git-fetch'.
+///
+/// Each hunk is a plist with the keys :id, the commit that last changed
+/// the lines, :author, :email and :time of that commit, :start, the
+/// first line of the hunk in the current file, :orig-start, the first
+/// line in the version of the file at :id, :count, the number of lines,
+/// :orig-path, the path of the file at :id, and :boundary, non-nil if
+/// the lines predate the history that was examined. Line numbers start
+/// at 1.
+///
+/// If NEWEST is non-nil, it is the revision to start blaming from
+/// instead of HEAD. Uncommitted changes are not taken into account.
+///
+/// Return the process used to communicate with the worker.
+#[lisp_fn(min = "3")]
+pub fn git_blame(
+    repo: LispObject,
+    path: LispStringRef,
+    callback: LispObject,
+    newest: LispObject,
+) -> LispObject {
+    let newest: Option<LispStringRef> = newest.into();
+    let newest = newest.map(|s| s.to_utf8());
+    let path = path.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        Ok((repo.path().to_path_buf(), relative_path(repo, &path)))
+    });
+    let (git_dir, path) = match result {
+        Ok(paths) => paths,
+        Err(e) => signal_git_error(e),
+    };
+
+    let proc = rust_worker(Qgit__blame_handler, move |_: String| {
+        UserData::new(blame(&git_dir, &path, newest.as_deref()))
+    });
+    unsafe { Fprocess_put(proc, QCcallback, callback) };
+
+    let mut pipe = unsafe { EmacsPipe::with_process(proc) };
+    if pipe.message_rust_worker(String::new()).is_err() {
+        error!("Failed to start blame worker");
+    }
+
+    proc
+}
+
+/// Internal handler receiving the result of `git-blame' from its worker.
+#[lisp_fn]
+pub fn git__blame_handler(proc: LispObject, data: LispObject) -> LispObject {
+    let result: BlameResult = unsafe { to_owned_userdata(data).unpack() };
+    let mut pipe = unsafe { EmacsPipe::with_process(proc) };
+    // Each call blames a single file, so the worker can go away now.
+    let _ = pipe.close_stream();
+
+    let (status, data) = match result {
+        Ok(entries) => (
+            Qdone,
+            list_from_vec(entries.iter().map(entry_to_lisp).collect()),
+        ),
+        Err(e) => (Qerror, error_data(&e)),
+    };
+
+    let callback = unsafe { Fprocess_get(proc, QCcallback) };
+    let mut args = [callback, status, data];
+    unsafe { Ffuncall(3, args.as_mut_ptr()) }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCboundary, ":boundary");
+    def_lisp_sym!(QCcallback, ":callback");
+    def_lisp_sym!(QCcount, ":count");
+    def_lisp_sym!(QCorig_path, ":orig-path");
+    def_lisp_sym!(QCorig_start, ":orig-start");
+    def_lisp_sym!(QCstart, ":start");
+    def_lisp_sym!(Qgit__blame_handler, "git--blame-handler");
+}
+
+include!(concat!(env!("OUT_DIR"), "/blame_exports.rs"));
@@ -0,0 +1,71 @@
+use std::os::raw::c_char;
+
+use lisp_macros::lisp_fn;
+
+use emacs_sys::bindings::globals;
+use emacs_sys::bindings::make_unibyte_string;
+use emacs_sys::bindings::Fcurrent_buffer;
+use emacs_sys::bindings::Fdecode_coding_string;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qundecided;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::error::signal_git_error;
+use crate::repository::relative_path;
+use crate::repository::with_repository;
+
+/// Insert the contents of a file as of some revision at point in BUFFER,
+/// or the current buffer if BUFFER is nil. Point does not move.
+///
+/// SPEC names the blob to insert in the repository containing REPO.
+/// With PATH, SPEC is a revision such as "HEAD~3" and PATH is the file
+/// to read from it, either absolute or relative to the top of the
+/// working tree. Without PATH, SPEC names the blob itself, as in
+/// "HEAD~3:src/lib.rs".
+///
+/// The contents are decoded using `coding-system-for-read' if it is
+/// non-nil, and otherwise the coding system is detected from them.
+/// Return the coding system that was used.
+#[lisp_fn(min = "2")]
+pub fn git_insert_blob(
+    repo: LispObject,
+    spec: LispStringRef,
+    path: LispObject,
+    buffer: LispObject,
+) -> LispObject {
+    let path: Option<LispStringRef> = path.into();
+    let path = path.map(|p| p.to_utf8());
+    let spec = spec.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        let spec = match &path {
+            Some(path) => format!("{}:{}", spec, relative_path(repo, path).display()),
+            None => spec,
+        };
+        let blob = repo.revparse_single(&spec)?.peel_to_blob()?;
+        Ok(blob.content().to_vec())
+    });
+
+    let content = match result {
+        Ok(content) => content,
+        Err(e) => signal_git_error(e),
+    };
+
+    let raw =
+        unsafe { make_unibyte_string(content.as_ptr() as *const c_char, content.len() as isize) };
+    let buffer = if buffer.is_nil() {
+        unsafe { Fcurrent_buffer() }
+    } else {
+        buffer
+    };
+    let coding = unsafe { globals.Vcoding_system_for_read };
+    let coding = if coding.is_nil() { Qundecided } else { coding };
+
+    unsafe {
+        Fdecode_coding_string(raw, coding, Qnil, buffer);
+        globals.Vlast_coding_system_used
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/blob_exports.rs"));
@@ -0,0 +1,131 @@
+use git2::build::CheckoutBuilder;
+use git2::BranchType;
+use git2::Repository;
+
+use lisp_macros::lisp_fn;
+
+use emacs_sys::lisp::LispObject;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::error::signal_git_error;
+use crate::repository::with_repository;
+
+fn branch_type(remote: bool) -> BranchType {
+    if remote {
+        BranchType::Remote
+    } else {
+        BranchType::Local
+    }
+}
+
+fn checkout(repo: &Repository, target: &str, force: bool) -> Result<(), git2::Error> {
+    let mut opts = CheckoutBuilder::new();
+    if force {
+        opts.force();
+    } else {
+        opts.safe();
+    }
+
+    match repo.find_branch(target, BranchType::Local) {
+        Ok(branch) => {
+            let reference = branch.into_reference();
+            let tree = reference.peel_to_tree()?;
+            repo.checkout_tree(tree.as_object(), Some(&mut opts))?;
+            let name = reference
+                .name()
+                .ok_or_else(|| git2::Error::from_str("Branch name is not valid UTF-8"))?;
+            repo.set_head(name)
+        }
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            let commit = repo.revparse_single(target)?.peel_to_commit()?;
+            repo.checkout_tree(commit.as_object(), Some(&mut opts))?;
+            repo.set_head_detached(commit.id())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Create a branch called NAME in the repository containing REPO.
+///
+/// The branch starts at the revision START, or at HEAD if START is nil.
+/// An existing branch called NAME is only overwritten if FORCE is
+/// non-nil. Return NAME.
+#[lisp_fn(min = "2")]
+pub fn git_branch_create(
+    repo: LispObject,
+    name: LispStringRef,
+    start: LispObject,
+    force: bool,
+) -> LispStringRef {
+    let start: Option<LispStringRef> = start.into();
+    let start = start.map_or_else(|| String::from("HEAD"), |s| s.to_utf8());
+    let branch = name.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        let commit = repo.revparse_single(&start)?.peel_to_commit()?;
+        repo.branch(&branch, &commit, force).map(|_| ())
+    });
+
+    match result {
+        Ok(()) => name,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Delete the branch called NAME in the repository containing REPO.
+/// If REMOTE is non-nil, NAME is a remote-tracking branch such as
+/// "origin/main".
+#[lisp_fn(min = "2")]
+pub fn git_branch_delete(repo: LispObject, name: LispStringRef, remote: bool) -> bool {
+    let branch = name.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        repo.find_branch(&branch, branch_type(remote))?.delete()
+    });
+
+    match result {
+        Ok(()) => true,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Rename the local branch OLD-NAME to NEW-NAME in the repository
+/// containing REPO. An existing branch called NEW-NAME is only
+/// overwritten if FORCE is non-nil. Return NEW-NAME.
+#[lisp_fn(min = "3")]
+pub fn git_branch_rename(
+    repo: LispObject,
+    old_name: LispStringRef,
+    new_name: LispStringRef,
+    force: bool,
+) -> LispStringRef {
+    let old_branch = old_name.to_utf8();
+    let new_branch = new_name.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        let mut branch = repo.find_branch(&old_branch, BranchType::Local)?;
+        branch.rename(&new_branch, force).map(|_| ())
+    });
+
+    match result {
+        Ok(()) => new_name,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Check out TARGET in the repository containing REPO.
+///
+/// TARGET is the name of a local branch, which HEAD then refers to, or
+/// any other revision, which detaches HEAD. Unless FORCE is non-nil,
+/// the checkout fails rather than overwrite local modifications.
+#[lisp_fn(min = "2")]
+pub fn git_checkout(repo: LispObject, target: LispStringRef, force: bool) -> LispStringRef {
+    let name = target.to_utf8();
+
+    match with_repository(repo, |repo| checkout(repo, &name, force)) {
+        Ok(()) => target,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/branch_exports.rs"));
@@ -0,0 +1,260 @@
+use git2::Commit;
+use git2::ErrorCode;
+use git2::Oid;
+use git2::Repository;
+use git2::Signature;
+
+use lisp_macros::lisp_fn;
+
+use emacs_sys::bindings::Ffuncall;
+use emacs_sys::globals::Qconsp;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::convert::oid_to_lisp;
+use crate::error::signal_git_error;
+use crate::repository::with_repository;
+use crate::repository::with_repository_mut;
+
+/// Who a commit is attributed to: the repository's configured
+/// identity, that of the commit being amended, or a given one.
+enum Identity {
+    Default,
+    Given(String, String),
+}
+
+impl Identity {
+    /// Parse nil, (NAME . EMAIL) or (NAME EMAIL).
+    fn from_lisp(obj: LispObject) -> Identity {
+        if obj.is_nil() {
+            return Identity::Default;
+        }
+        let cons = match obj.as_cons() {
+            Some(cons) => cons,
+            None => {
+                wrong_type!(Qconsp, obj);
+            }
+        };
+        let email = match cons.cdr().as_cons() {
+            Some(rest) => rest.car(),
+            None => cons.cdr(),
+        };
+        let name: LispStringRef = cons.car().into();
+        let email: LispStringRef = email.into();
+        Identity::Given(name.to_utf8(), email.to_utf8())
+    }
+
+    fn signature(
+        &self,
+        repo: &Repository,
+        amended: Option<&Signature>,
+    ) -> Result<Signature<'static>, git2::Error> {
+        match (self, amended) {
+            (Identity::Given(name, email), _) => Signature::now(name, email),
+            (Identity::Default, Some(sig)) => Ok(sig.to_owned()),
+            (Identity::Default, None) => repo.signature(),
+        }
+    }
+}
+
+/// The commit HEAD points to, or None on an unborn branch.
+fn head_commit(repo: &Repository) -> Result<Option<Commit<'_>>, git2::Error> {
+    match repo.head() {
+        Ok(head) => Ok(Some(head.peel_to_commit()?)),
+        Err(e) if e.code() == ErrorCode::UnbornBranch => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Point HEAD, or the branch it refers to, at the commit ID.
+pub fn update_head(repo: &Repository, id: Oid, log_message: &str) -> Result<(), git2::Error> {
+    let head = repo.find_reference("HEAD")?;
+    match head.symbolic_target() {
+        Some(name) => {
+            repo.reference(name, id, true, log_message)?;
+        }
+        None => repo.set_head_detached(id)?,
+    }
+    Ok(())
+}
+
+/// Everything needed to create a commit, gathered before any Lisp code
+/// gets to sign it.
+struct PendingCommit {
+    content: String,
+    log_message: String,
+    merge: bool,
+}
+
+/// The commits being merged into HEAD by a merge that is in progress.
+fn merge_heads(repo: &mut Repository) -> Result<Vec<Oid>, git2::Error> {
+    let mut ids = Vec::new();
+    match repo.mergehead_foreach(|id| {
+        ids.push(*id);
+        true
+    }) {
+        Ok(()) => Ok(ids),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn prepare_commit(
+    repo: &mut Repository,
+    message: Option<String>,
+    author: &Identity,
+    committer: &Identity,
+    amend: bool,
+) -> Result<PendingCommit, git2::Error> {
+    let merged = if amend {
+        Vec::new()
+    } else {
+        merge_heads(repo)?
+    };
+    let repo = &*repo;
+    let head = head_commit(repo)?;
+    let amended = match (amend, &head) {
+        (true, Some(commit)) => Some(commit),
+        (true, None) => {
+            return Err(git2::Error::from_str("There is no commit to amend"));
+        }
+        (false, _) => None,
+    };
+
+    let message = match (message, amended) {
+        (Some(message), _) => message,
+        (None, Some(commit)) => String::from_utf8_lossy(commit.message_bytes()).into_owned(),
+        (None, None) => return Err(git2::Error::from_str("A commit message is required")),
+    };
+    let author = author.signature(repo, amended.map(|c| c.author()).as_ref())?;
+    let committer = committer.signature(repo, None)?;
+
+    let mut index = repo.index()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let mut parents: Vec<Commit> = match (amended, &head) {
+        (Some(commit), _) => commit.parents().collect(),
+        (None, Some(commit)) => vec![commit.clone()],
+        (None, None) => Vec::new(),
+    };
+    for id in &merged {
+        parents.push(repo.find_commit(*id)?);
+    }
+    let parents: Vec<&Commit> = parents.iter().collect();
+
+    let buf = repo.commit_create_buffer(&author, &committer, &message, &tree, &parents)?;
+    let content = buf
+        .as_str()
+        .ok_or_else(|| git2::Error::from_str("Commit is not valid UTF-8"))?
+        .to_string();
+
+    let kind = if amend {
+        "commit (amend)"
+    } else if parents.is_empty() {
+        "commit (initial)"
+    } else if !merged.is_empty() {
+        "commit (merge)"
+    } else {
+        "commit"
+    };
+    let summary = message.lines().next().unwrap_or_default();
+
+    Ok(PendingCommit {
+        content,
+        log_message: format!("{}: {}", kind, summary),
+        merge: !merged.is_empty(),
+    })
+}
+
+fn finish_commit(
+    repo: &Repository,
+    pending: &PendingCommit,
+    signature: Option<String>,
+) -> Result<Oid, git2::Error> {
+    let id = match signature {
+        Some(signature) => repo.commit_signed(&pending.content, &signature, None)?,
+        None => repo
+            .odb()?
+            .write(git2::ObjectType::Commit, pending.content.as_bytes())?,
+    };
+    update_head(repo, id, &pending.log_message)?;
+    if pending.merge {
+        repo.cleanup_state()?;
+    }
+    Ok(id)
+}
+
+/// Write the index of the repository containing REPO as a tree object.
+/// Return the id of the tree.
+#[lisp_fn]
+pub fn git_write_tree(repo: LispObject) -> LispObject {
+    match with_repository(repo, |repo| repo.index()?.write_tree()) {
+        Ok(id) => oid_to_lisp(id),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Commit the index of the repository containing REPO with MESSAGE.
+///
+/// AUTHOR and COMMITTER are either nil, to use the identity configured
+/// for the repository, or a cons (NAME . EMAIL). If AMEND is non-nil,
+/// replace the commit at HEAD instead of adding a new one on top of it.
+/// When amending, a nil MESSAGE or AUTHOR keeps those of the replaced
+/// commit.
+///
+/// If SIGN-FUNCTION is non-nil, it is called with the text of the
+/// commit object and should return the signature to embed in it, such
+/// as an ASCII-armored GPG signature, or nil to leave it unsigned.
+///
+/// While a merge is in progress, the commits being merged become
+/// additional parents and the merge state is cleared.
+///
+/// The branch HEAD refers to is moved to the new commit, whose id is
+/// returned.
+#[lisp_fn(min = "2")]
+pub fn git_commit(
+    repo: LispObject,
+    message: LispObject,
+    author: LispObject,
+    committer: LispObject,
+    amend: bool,
+    sign_function: LispObject,
+) -> LispObject {
+    let message: Option<LispStringRef> = if message.is_nil() {
+        None
+    } else {
+        Some(message.into())
+    };
+    let message = message.map(|m| m.to_utf8());
+    let author = Identity::from_lisp(author);
+    let committer = Identity::from_lisp(committer);
+
+    let pending = match with_repository_mut(repo, |repo| {
+        prepare_commit(repo, message, &author, &committer, amend)
+    }) {
+        Ok(pending) => pending,
+        Err(e) => signal_git_error(e),
+    };
+
+    // The repository is not held open while Lisp code runs, since a
+    // non-local exit from it would skip dropping it.
+    let signature = if sign_function.is_nil() {
+        None
+    } else {
+        let content: LispObject = pending.content.as_str().into();
+        let mut args = [sign_function, content];
+        let signature = unsafe { Ffuncall(2, args.as_mut_ptr()) };
+        if signature.is_nil() {
+            None
+        } else {
+            let signature: LispStringRef = signature.into();
+            Some(signature.to_utf8())
+        }
+    };
+
+    match with_repository(repo, |repo| finish_commit(repo, &pending, signature)) {
+        Ok(id) => oid_to_lisp(id),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/commit_exports.rs"));
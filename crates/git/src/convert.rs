@@ -0,0 +1,87 @@
+//! Conversions between Lisp objects and the values handed to git2.
+
+use git2::Commit;
+use git2::Oid;
+
+use emacs_sys::bindings::make_int;
+use emacs_sys::globals::QCauthor;
+use emacs_sys::globals::QCemail;
+use emacs_sys::globals::QCid;
+use emacs_sys::globals::QCparents;
+use emacs_sys::globals::QCsummary;
+use emacs_sys::globals::QCtime;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qstringp;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::list::LispConsCircularChecks;
+use emacs_sys::list::LispConsEndChecks;
+
+/// Build a Lisp list holding ITEMS, preserving their order.
+pub fn list_from_vec(items: Vec<LispObject>) -> LispObject {
+    items
+        .into_iter()
+        .rev()
+        .fold(Qnil, |acc, item| LispObject::cons(item, acc))
+}
+
+/// Turn OBJ, which may be nil, a string or a list of strings, into a
+/// vector of Rust strings.
+pub fn string_list(obj: LispObject) -> Vec<String> {
+    if obj.is_nil() {
+        vec![]
+    } else if let Some(s) = obj.as_string() {
+        vec![s.to_utf8()]
+    } else {
+        obj.iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on)
+            .map(|x| match x.as_string() {
+                Some(s) => s.to_utf8(),
+                None => {
+                    wrong_type!(Qstringp, x);
+                }
+            })
+            .collect()
+    }
+}
+
+/// Convert bytes reported by libgit2, such as paths, names or commit
+/// messages, into a Lisp string. These are stored as raw bytes in the
+/// repository, so invalid UTF-8 is replaced rather than rejected.
+pub fn bytes_to_lisp(bytes: &[u8]) -> LispObject {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+pub fn oid_to_lisp(oid: Oid) -> LispObject {
+    oid.to_string().into()
+}
+
+/// Describe COMMIT as a plist with the keys :id, :author, :email,
+/// :time, :summary and :parents. :time is in seconds since the epoch
+/// and :parents is a list of commit ids.
+pub fn commit_to_lisp(commit: &Commit) -> LispObject {
+    let author = commit.author();
+    let parents = list_from_vec(commit.parent_ids().map(oid_to_lisp).collect());
+    list!(
+        QCid,
+        oid_to_lisp(commit.id()),
+        QCauthor,
+        bytes_to_lisp(author.name_bytes()),
+        QCemail,
+        bytes_to_lisp(author.email_bytes()),
+        QCtime,
+        unsafe { make_int(commit.time().seconds()) },
+        QCsummary,
+        commit.summary_bytes().map_or(Qnil, bytes_to_lisp),
+        QCparents,
+        parents
+    )
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCid, ":id");
+    def_lisp_sym!(QCauthor, ":author");
+    def_lisp_sym!(QCemail, ":email");
+    def_lisp_sym!(QCtime, ":time");
+    def_lisp_sym!(QCsummary, ":summary");
+    def_lisp_sym!(QCparents, ":parents");
+}
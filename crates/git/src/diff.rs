@@ -0,0 +1,262 @@
+use git2::Delta;
+use git2::Diff;
+use git2::DiffLine;
+use git2::DiffOptions;
+use git2::Patch;
+use git2::Repository;
+use git2::Tree;
+
+use lisp_macros::lisp_fn;
+
+use emacs_sys::globals::QCbinary;
+use emacs_sys::globals::QCheader;
+use emacs_sys::globals::QChunks;
+use emacs_sys::globals::QClines;
+use emacs_sys::globals::QCnew_file;
+use emacs_sys::globals::QCnew_lines;
+use emacs_sys::globals::QCnew_start;
+use emacs_sys::globals::QCold_file;
+use emacs_sys::globals::QCold_lines;
+use emacs_sys::globals::QCold_start;
+use emacs_sys::globals::QCstatus;
+use emacs_sys::globals::Qadded;
+use emacs_sys::globals::Qconflicted;
+use emacs_sys::globals::Qcontext;
+use emacs_sys::globals::Qcopied;
+use emacs_sys::globals::Qdeleted;
+use emacs_sys::globals::Qeofnl;
+use emacs_sys::globals::Qignored;
+use emacs_sys::globals::Qindex;
+use emacs_sys::globals::Qmodified;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qrenamed;
+use emacs_sys::globals::Qtypechange;
+use emacs_sys::globals::Qunmodified;
+use emacs_sys::globals::Qunreadable;
+use emacs_sys::globals::Quntracked;
+use emacs_sys::globals::Qworktree;
+use emacs_sys::lisp::LispObject;
+
+use crate::convert::bytes_to_lisp;
+use crate::convert::list_from_vec;
+use crate::convert::string_list;
+use crate::error::signal_git_error;
+use crate::repository::with_repository;
+
+/// One side of a comparison made by `git-diff'.
+pub enum DiffSide {
+    Index,
+    Worktree,
+    Tree(String),
+}
+
+impl DiffSide {
+    /// Parse OBJ, one of the symbols `index' and `worktree' or a
+    /// revision string, falling back to DEFAULT when it is nil.
+    pub fn from_lisp(obj: LispObject, default: DiffSide) -> DiffSide {
+        match obj {
+            Qnil => default,
+            Qindex => DiffSide::Index,
+            Qworktree => DiffSide::Worktree,
+            _ => match obj.as_string() {
+                Some(rev) => DiffSide::Tree(rev.to_utf8()),
+                None => error!("Diff side must be 'index, 'worktree or a revision string"),
+            },
+        }
+    }
+}
+
+fn resolve_tree<'r>(repo: &'r Repository, rev: &str) -> Result<Tree<'r>, git2::Error> {
+    repo.revparse_single(rev)?.peel_to_tree()
+}
+
+/// Compute the diff going from OLD to NEW in REPO.
+pub fn make_diff<'r>(
+    repo: &'r Repository,
+    old: &DiffSide,
+    new: &DiffSide,
+    opts: &mut DiffOptions,
+) -> Result<Diff<'r>, git2::Error> {
+    match (old, new) {
+        (DiffSide::Index, DiffSide::Worktree) => repo.diff_index_to_workdir(None, Some(opts)),
+        (DiffSide::Tree(rev), DiffSide::Index) => {
+            let tree = resolve_tree(repo, rev)?;
+            repo.diff_tree_to_index(Some(&tree), None, Some(opts))
+        }
+        (DiffSide::Tree(rev), DiffSide::Worktree) => {
+            let tree = resolve_tree(repo, rev)?;
+            repo.diff_tree_to_workdir_with_index(Some(&tree), Some(opts))
+        }
+        (DiffSide::Tree(old_rev), DiffSide::Tree(new_rev)) => {
+            let old_tree = resolve_tree(repo, old_rev)?;
+            let new_tree = resolve_tree(repo, new_rev)?;
+            repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), Some(opts))
+        }
+        (DiffSide::Index, DiffSide::Index) | (DiffSide::Worktree, DiffSide::Worktree) => Err(
+            git2::Error::from_str("Cannot compare the index or worktree against itself"),
+        ),
+        // The remaining combinations are the ones above, backwards.
+        _ => {
+            opts.reverse(true);
+            make_diff(repo, new, old, opts)
+        }
+    }
+}
+
+pub fn delta_status(status: Delta) -> LispObject {
+    match status {
+        Delta::Unmodified => Qunmodified,
+        Delta::Added => Qadded,
+        Delta::Deleted => Qdeleted,
+        Delta::Modified => Qmodified,
+        Delta::Renamed => Qrenamed,
+        Delta::Copied => Qcopied,
+        Delta::Ignored => Qignored,
+        Delta::Untracked => Quntracked,
+        Delta::Typechange => Qtypechange,
+        Delta::Unreadable => Qunreadable,
+        Delta::Conflicted => Qconflicted,
+    }
+}
+
+fn line_to_lisp(line: &DiffLine) -> LispObject {
+    let origin = match line.origin() {
+        '+' => Qadded,
+        '-' => Qdeleted,
+        '=' | '>' | '<' => Qeofnl,
+        _ => Qcontext,
+    };
+
+    list!(
+        origin,
+        line.old_lineno().map_or(Qnil, LispObject::from),
+        line.new_lineno().map_or(Qnil, LispObject::from),
+        bytes_to_lisp(line.content())
+    )
+}
+
+fn hunks_to_lisp(patch: &Patch) -> Result<LispObject, git2::Error> {
+    let mut hunks = Vec::with_capacity(patch.num_hunks());
+    for hunk_idx in 0..patch.num_hunks() {
+        let (hunk, num_lines) = patch.hunk(hunk_idx)?;
+        let mut lines = Vec::with_capacity(num_lines);
+        for line_idx in 0..num_lines {
+            lines.push(line_to_lisp(&patch.line_in_hunk(hunk_idx, line_idx)?));
+        }
+
+        hunks.push(list!(
+            QCheader,
+            bytes_to_lisp(hunk.header()),
+            QCold_start,
+            hunk.old_start(),
+            QCold_lines,
+            hunk.old_lines(),
+            QCnew_start,
+            hunk.new_start(),
+            QCnew_lines,
+            hunk.new_lines(),
+            QClines,
+            list_from_vec(lines)
+        ));
+    }
+
+    Ok(list_from_vec(hunks))
+}
+
+fn diff_to_lisp(diff: &Diff) -> Result<LispObject, git2::Error> {
+    let mut files = Vec::with_capacity(diff.deltas().len());
+    for (idx, delta) in diff.deltas().enumerate() {
+        let hunks = match Patch::from_diff(diff, idx)? {
+            Some(patch) => hunks_to_lisp(&patch)?,
+            None => Qnil,
+        };
+
+        files.push(list!(
+            QCstatus,
+            delta_status(delta.status()),
+            QCold_file,
+            delta.old_file().path_bytes().map_or(Qnil, bytes_to_lisp),
+            QCnew_file,
+            delta.new_file().path_bytes().map_or(Qnil, bytes_to_lisp),
+            QCbinary,
+            delta.flags().is_binary(),
+            QChunks,
+            hunks
+        ));
+    }
+
+    Ok(list_from_vec(files))
+}
+
+/// Return the differences between OLD and NEW in the repository
+/// containing REPO.
+///
+/// OLD and NEW are each the symbol `index', the symbol `worktree' or a
+/// revision string such as "HEAD" or "main~2". OLD defaults to the
+/// index and NEW to the working tree, so that with neither given the
+/// result describes the unstaged changes. Use "HEAD" and `index' for
+/// the staged changes.
+///
+/// PATHSPEC, if non-nil, is a string or a list of strings limiting the
+/// diff to matching paths. CONTEXT-LINES is the number of unchanged
+/// lines shown around each hunk and defaults to 3.
+///
+/// The result is a list of plists, one per changed file, with the keys
+/// :status, :old-file, :new-file, :binary and :hunks. Each hunk is a
+/// plist with the keys :header, :old-start, :old-lines, :new-start,
+/// :new-lines and :lines, and each line is a list (ORIGIN OLD-LINENO
+/// NEW-LINENO CONTENT), where ORIGIN is one of the symbols `context',
+/// `added', `deleted' or `eofnl' and the line numbers are nil for
+/// lines missing on that side.
+#[lisp_fn(min = "1")]
+pub fn git_diff(
+    repo: LispObject,
+    old: LispObject,
+    new: LispObject,
+    pathspec: LispObject,
+    context_lines: LispObject,
+) -> LispObject {
+    let old = DiffSide::from_lisp(old, DiffSide::Index);
+    let new = DiffSide::from_lisp(new, DiffSide::Worktree);
+    let mut opts = DiffOptions::new();
+    for spec in string_list(pathspec) {
+        opts.pathspec(spec);
+    }
+    if context_lines.is_not_nil() {
+        opts.context_lines(context_lines.as_natnum_or_error() as u32);
+    }
+
+    let result = with_repository(repo, |repo| {
+        let diff = make_diff(repo, &old, &new, &mut opts)?;
+        diff_to_lisp(&diff)
+    });
+
+    match result {
+        Ok(files) => files,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(Qindex, "index");
+    def_lisp_sym!(Qworktree, "worktree");
+    def_lisp_sym!(Qcopied, "copied");
+    def_lisp_sym!(Qunmodified, "unmodified");
+    def_lisp_sym!(Qunreadable, "unreadable");
+    def_lisp_sym!(Qcontext, "context");
+    def_lisp_sym!(Qeofnl, "eofnl");
+    def_lisp_sym!(QCstatus, ":status");
+    def_lisp_sym!(QCold_file, ":old-file");
+    def_lisp_sym!(QCnew_file, ":new-file");
+    def_lisp_sym!(QCbinary, ":binary");
+    def_lisp_sym!(QChunks, ":hunks");
+    def_lisp_sym!(QCheader, ":header");
+    def_lisp_sym!(QCold_start, ":old-start");
+    def_lisp_sym!(QCold_lines, ":old-lines");
+    def_lisp_sym!(QCnew_start, ":new-start");
+    def_lisp_sym!(QCnew_lines, ":new-lines");
+    def_lisp_sym!(QClines, ":lines");
+}
+
+include!(concat!(env!("OUT_DIR"), "/diff_exports.rs"));
@@ -0,0 +1,142 @@
+//! The `git-error' condition hierarchy signaled for libgit2 failures.
+
+use git2::ErrorClass;
+use git2::ErrorCode;
+
+use emacs_sys::bindings::Fintern;
+use emacs_sys::bindings::Fput;
+use emacs_sys::eval::signal_rust;
+use emacs_sys::globals::Qerror;
+use emacs_sys::globals::Qerror_conditions;
+use emacs_sys::globals::Qerror_message;
+use emacs_sys::globals::Qgit_auth;
+use emacs_sys::globals::Qgit_bare_repository;
+use emacs_sys::globals::Qgit_conflict;
+use emacs_sys::globals::Qgit_dirty;
+use emacs_sys::globals::Qgit_error;
+use emacs_sys::globals::Qgit_exists;
+use emacs_sys::globals::Qgit_invalid;
+use emacs_sys::globals::Qgit_invalid_spec;
+use emacs_sys::globals::Qgit_locked;
+use emacs_sys::globals::Qgit_not_fast_forward;
+use emacs_sys::globals::Qgit_not_found;
+use emacs_sys::globals::Qgit_unborn_branch;
+use emacs_sys::globals::Qnil;
+use emacs_sys::lisp::LispObject;
+
+/// The conditions below `git-error' and their messages.
+fn conditions() -> [(LispObject, &'static str); 11] {
+    [
+        (Qgit_not_found, "Git object not found"),
+        (Qgit_exists, "Git object already exists"),
+        (Qgit_conflict, "Git conflict"),
+        (Qgit_locked, "Git resource is locked"),
+        (Qgit_auth, "Git authentication failed"),
+        (Qgit_invalid_spec, "Invalid Git specification"),
+        (Qgit_invalid, "Invalid Git operation"),
+        (Qgit_dirty, "Git working tree has local changes"),
+        (
+            Qgit_not_fast_forward,
+            "Git reference cannot be fast-forwarded",
+        ),
+        (Qgit_unborn_branch, "Git branch has no commits yet"),
+        (Qgit_bare_repository, "Git operation needs a working tree"),
+    ]
+}
+
+/// Give the `git-error' conditions their properties. The symbols of the
+/// crate are defined before `error' is set up, so this is called later
+/// on, from `syms_of_git'.
+fn define_git_errors() {
+    unsafe {
+        Fput(Qgit_error, Qerror_conditions, list!(Qgit_error, Qerror));
+        Fput(Qgit_error, Qerror_message, LispObject::from("Git error"));
+        for (symbol, message) in conditions().iter() {
+            Fput(
+                *symbol,
+                Qerror_conditions,
+                list!(*symbol, Qgit_error, Qerror),
+            );
+            Fput(*symbol, Qerror_message, LispObject::from(*message));
+        }
+    }
+}
+
+fn condition(code: ErrorCode) -> LispObject {
+    match code {
+        ErrorCode::NotFound => Qgit_not_found,
+        ErrorCode::Exists => Qgit_exists,
+        ErrorCode::Conflict | ErrorCode::MergeConflict | ErrorCode::Unmerged => Qgit_conflict,
+        ErrorCode::Locked => Qgit_locked,
+        ErrorCode::Auth | ErrorCode::Certificate => Qgit_auth,
+        ErrorCode::InvalidSpec | ErrorCode::Ambiguous => Qgit_invalid_spec,
+        ErrorCode::Invalid => Qgit_invalid,
+        ErrorCode::Modified | ErrorCode::Uncommitted | ErrorCode::IndexDirty => Qgit_dirty,
+        ErrorCode::NotFastForward => Qgit_not_fast_forward,
+        ErrorCode::UnbornBranch => Qgit_unborn_branch,
+        ErrorCode::BareRepo => Qgit_bare_repository,
+        _ => Qgit_error,
+    }
+}
+
+/// Turn the name of a libgit2 error class or code, as in `NotFound',
+/// into a Lisp symbol such as `not-found'.
+fn name_to_symbol(name: String) -> LispObject {
+    let mut symbol = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            symbol.push('-');
+        }
+        symbol.push(c.to_ascii_lowercase());
+    }
+    unsafe { Fintern(LispObject::from(symbol), Qnil) }
+}
+
+/// Describe E as the error `signal_git_error' signals, that is the list
+/// (CONDITION MESSAGE CLASS CODE) a `condition-case' handler is given,
+/// where CLASS and CODE are symbols naming the libgit2 error class and
+/// code, such as `reference' and `not-found'. Failures of work done off
+/// the Lisp thread are reported to callbacks in this form.
+pub fn error_data(e: &git2::Error) -> LispObject {
+    let class = match e.class() {
+        ErrorClass::None => Qnil,
+        class => name_to_symbol(format!("{:?}", class)),
+    };
+    list!(
+        condition(e.code()),
+        LispObject::from(e.message()),
+        class,
+        name_to_symbol(format!("{:?}", e.code()))
+    )
+}
+
+/// Signal E as a `git-error' condition, with the data described in
+/// `error_data'.
+pub fn signal_git_error(e: git2::Error) -> ! {
+    let error = error_data(&e);
+    // Signaling never returns, so E must be dropped before.
+    drop(e);
+    let (condition, data) = error.into();
+    signal_rust(condition, data);
+}
+
+#[no_mangle]
+pub extern "C" fn syms_of_git() {
+    define_git_errors();
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(Qgit_auth, "git-auth");
+    def_lisp_sym!(Qgit_bare_repository, "git-bare-repository");
+    def_lisp_sym!(Qgit_conflict, "git-conflict");
+    def_lisp_sym!(Qgit_dirty, "git-dirty");
+    def_lisp_sym!(Qgit_error, "git-error");
+    def_lisp_sym!(Qgit_exists, "git-exists");
+    def_lisp_sym!(Qgit_invalid, "git-invalid");
+    def_lisp_sym!(Qgit_invalid_spec, "git-invalid-spec");
+    def_lisp_sym!(Qgit_locked, "git-locked");
+    def_lisp_sym!(Qgit_not_fast_forward, "git-not-fast-forward");
+    def_lisp_sym!(Qgit_not_found, "git-not-found");
+    def_lisp_sym!(Qgit_unborn_branch, "git-unborn-branch");
+}
@@ -1,3 +1,4 @@
+#![allow(non_camel_case_types, non_snake_case, non_upper_case_globals)]
 #![feature(concat_idents)]
 #![feature(lazy_cell)]
 
@@ -7,7 +8,24 @@ extern crate lisp_macros;
 #[macro_use]
 extern crate lisp_util;
 
+mod blame;
+mod blob;
+mod branch;
+mod commit;
+mod convert;
+mod diff;
+mod error;
+mod merge;
+mod refs;
+mod remote;
 mod repository;
+mod revwalk;
+mod stage;
+mod stash;
+mod status;
+mod submodule;
+mod tag;
+mod worktree;
 
 #[cfg(not(test))]
 include!(concat!(env!("OUT_DIR"), "/c_exports.rs"));
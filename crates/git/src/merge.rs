@@ -0,0 +1,351 @@
+use git2::build::CheckoutBuilder;
+use git2::AnnotatedCommit;
+use git2::ErrorCode;
+use git2::Index;
+use git2::IndexEntry;
+use git2::MergeAnalysis;
+use git2::MergePreference;
+use git2::Oid;
+use git2::RebaseOptions;
+use git2::Repository;
+
+use lisp_macros::lisp_fn;
+
+use emacs_sys::globals::QCanalysis;
+use emacs_sys::globals::QCancestor;
+use emacs_sys::globals::QCcommit;
+use emacs_sys::globals::QCconflicts;
+use emacs_sys::globals::QChead;
+use emacs_sys::globals::QCours;
+use emacs_sys::globals::QCpath;
+use emacs_sys::globals::QCpreference;
+use emacs_sys::globals::QCstatus;
+use emacs_sys::globals::QCtheirs;
+use emacs_sys::globals::Qconflict;
+use emacs_sys::globals::Qdone;
+use emacs_sys::globals::Qfast_forward;
+use emacs_sys::globals::Qfast_forward_only;
+use emacs_sys::globals::Qmerged;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qno_fast_forward;
+use emacs_sys::globals::Qnormal;
+use emacs_sys::globals::Qunborn;
+use emacs_sys::globals::Qup_to_date;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::commit::update_head;
+use crate::convert::bytes_to_lisp;
+use crate::convert::list_from_vec;
+use crate::convert::oid_to_lisp;
+use crate::error::signal_git_error;
+use crate::repository::with_repository;
+
+/// A conflicted index entry, copied out of the index.
+struct Conflict {
+    path: Vec<u8>,
+    ancestor: Option<Oid>,
+    ours: Option<Oid>,
+    theirs: Option<Oid>,
+}
+
+impl Conflict {
+    fn to_lisp(&self) -> LispObject {
+        list!(
+            QCpath,
+            bytes_to_lisp(&self.path),
+            QCancestor,
+            self.ancestor.map_or(Qnil, oid_to_lisp),
+            QCours,
+            self.ours.map_or(Qnil, oid_to_lisp),
+            QCtheirs,
+            self.theirs.map_or(Qnil, oid_to_lisp)
+        )
+    }
+}
+
+fn conflicts(index: &Index) -> Result<Vec<Conflict>, git2::Error> {
+    index
+        .conflicts()?
+        .map(|conflict| {
+            let conflict = conflict?;
+            let path = [&conflict.our, &conflict.their, &conflict.ancestor]
+                .iter()
+                .find_map(|entry| entry.as_ref().map(|e| e.path.clone()))
+                .unwrap_or_default();
+            let id = |entry: &Option<IndexEntry>| entry.as_ref().map(|e| e.id);
+            Ok(Conflict {
+                path,
+                ancestor: id(&conflict.ancestor),
+                ours: id(&conflict.our),
+                theirs: id(&conflict.their),
+            })
+        })
+        .collect()
+}
+
+fn conflicts_to_lisp(conflicts: &[Conflict]) -> LispObject {
+    list_from_vec(conflicts.iter().map(Conflict::to_lisp).collect())
+}
+
+fn annotated_commit<'r>(
+    repo: &'r Repository,
+    revision: &str,
+) -> Result<AnnotatedCommit<'r>, git2::Error> {
+    match repo.revparse_ext(revision)? {
+        (_, Some(reference)) => repo.reference_to_annotated_commit(&reference),
+        (object, None) => repo.find_annotated_commit(object.peel_to_commit()?.id()),
+    }
+}
+
+/// Check out the commit ID and move HEAD to it.
+fn move_head(repo: &Repository, id: Oid, log_message: &str) -> Result<(), git2::Error> {
+    let commit = repo.find_commit(id)?;
+    repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))?;
+    update_head(repo, id, log_message)
+}
+
+fn analysis_to_lisp(analysis: MergeAnalysis) -> LispObject {
+    let flags = [
+        (MergeAnalysis::ANALYSIS_UP_TO_DATE, Qup_to_date),
+        (MergeAnalysis::ANALYSIS_FASTFORWARD, Qfast_forward),
+        (MergeAnalysis::ANALYSIS_NORMAL, Qnormal),
+        (MergeAnalysis::ANALYSIS_UNBORN, Qunborn),
+    ];
+    list_from_vec(
+        flags
+            .iter()
+            .filter(|(flag, _)| analysis.contains(*flag))
+            .map(|(_, symbol)| *symbol)
+            .collect(),
+    )
+}
+
+fn preference_to_lisp(preference: MergePreference) -> LispObject {
+    if preference.contains(MergePreference::NO_FAST_FORWARD) {
+        Qno_fast_forward
+    } else if preference.contains(MergePreference::FASTFORWARD_ONLY) {
+        Qfast_forward_only
+    } else {
+        Qnil
+    }
+}
+
+/// Analyze how REVISION could be merged into HEAD in the repository
+/// containing REPO.
+///
+/// Return a plist with the keys :analysis, a list of the symbols
+/// `up-to-date', `fast-forward', `normal' and `unborn' that apply, and
+/// :preference, the configured merge.ff setting as `no-fast-forward',
+/// `fast-forward-only' or nil.
+#[lisp_fn]
+pub fn git_merge_analysis(repo: LispObject, revision: LispStringRef) -> LispObject {
+    let revision = revision.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        let theirs = annotated_commit(repo, &revision)?;
+        repo.merge_analysis(&[&theirs])
+    });
+
+    match result {
+        Ok((analysis, preference)) => list!(
+            QCanalysis,
+            analysis_to_lisp(analysis),
+            QCpreference,
+            preference_to_lisp(preference)
+        ),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+enum MergeOutcome {
+    UpToDate,
+    FastForward,
+    Merged,
+    Conflict(Vec<Conflict>),
+}
+
+fn merge(repo: &Repository, revision: &str, no_ff: bool) -> Result<MergeOutcome, git2::Error> {
+    let theirs = annotated_commit(repo, revision)?;
+    let (analysis, preference) = repo.merge_analysis(&[&theirs])?;
+    let no_ff = no_ff || preference.contains(MergePreference::NO_FAST_FORWARD);
+
+    if analysis.is_up_to_date() {
+        return Ok(MergeOutcome::UpToDate);
+    }
+    if analysis.is_unborn() || (analysis.is_fast_forward() && !no_ff) {
+        let log_message = format!("merge {}: Fast-forward", revision);
+        move_head(repo, theirs.id(), &log_message)?;
+        return Ok(MergeOutcome::FastForward);
+    }
+    if preference.contains(MergePreference::FASTFORWARD_ONLY) {
+        return Err(git2::Error::from_str("Not possible to fast-forward"));
+    }
+
+    repo.merge(&[&theirs], None, Some(CheckoutBuilder::new().safe()))?;
+    let index = repo.index()?;
+    if index.has_conflicts() {
+        Ok(MergeOutcome::Conflict(conflicts(&index)?))
+    } else {
+        Ok(MergeOutcome::Merged)
+    }
+}
+
+/// Merge REVISION into HEAD in the repository containing REPO.
+///
+/// When HEAD can be fast-forwarded, it is, unless NO-FAST-FORWARD is
+/// non-nil or the repository is configured against it. Otherwise the
+/// merge result is written to the index and working tree, and is
+/// completed by `git-commit'.
+///
+/// Return a plist whose :status is `up-to-date', `fast-forward',
+/// `merged' when the merge is ready to be committed, or `conflict'.
+/// In the last case, :conflicts holds the conflicts as returned by
+/// `git-conflicts'.
+#[lisp_fn(min = "2")]
+pub fn git_merge(repo: LispObject, revision: LispStringRef, no_fast_forward: bool) -> LispObject {
+    let revision = revision.to_utf8();
+
+    match with_repository(repo, |repo| merge(repo, &revision, no_fast_forward)) {
+        Ok(MergeOutcome::UpToDate) => list!(QCstatus, Qup_to_date),
+        Ok(MergeOutcome::FastForward) => list!(QCstatus, Qfast_forward),
+        Ok(MergeOutcome::Merged) => list!(QCstatus, Qmerged),
+        Ok(MergeOutcome::Conflict(conflicts)) => list!(
+            QCstatus,
+            Qconflict,
+            QCconflicts,
+            conflicts_to_lisp(&conflicts)
+        ),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Abort the merge in progress in the repository containing REPO,
+/// resetting the index and working tree to HEAD.
+#[lisp_fn]
+pub fn git_merge_abort(repo: LispObject) -> bool {
+    let result = with_repository(repo, |repo| {
+        let head = repo.head()?.peel(git2::ObjectType::Commit)?;
+        repo.reset(&head, git2::ResetType::Hard, None)?;
+        repo.cleanup_state()
+    });
+
+    match result {
+        Ok(()) => true,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Return the conflicted entries in the index of the repository
+/// containing REPO.
+///
+/// Each is a plist with the keys :path, and :ancestor, :ours and
+/// :theirs, the ids of the blobs on each side of the conflict, or nil
+/// for a side where the file does not exist.
+#[lisp_fn]
+pub fn git_conflicts(repo: LispObject) -> LispObject {
+    match with_repository(repo, |repo| conflicts(&repo.index()?)) {
+        Ok(conflicts) => conflicts_to_lisp(&conflicts),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+enum RebaseOutcome {
+    Done(Oid),
+    Conflict(Oid, Vec<Conflict>),
+}
+
+fn rebase(
+    repo: &Repository,
+    upstream: &str,
+    onto: Option<&str>,
+) -> Result<RebaseOutcome, git2::Error> {
+    let head = repo.head()?.peel_to_commit()?.id();
+    let upstream = annotated_commit(repo, upstream)?;
+    let onto = onto.map(|onto| annotated_commit(repo, onto)).transpose()?;
+    let base = onto.as_ref().unwrap_or(&upstream).id();
+
+    let mut opts = RebaseOptions::new();
+    opts.inmemory(true);
+    let mut rebase = repo.rebase(None, Some(&upstream), onto.as_ref(), Some(&mut opts))?;
+    let committer = repo.signature()?;
+
+    // With nothing to replay, the result is the commit rebased onto.
+    let mut last = base;
+    while let Some(operation) = rebase.next() {
+        let id = operation?.id();
+        let index = rebase.inmemory_index()?;
+        if index.has_conflicts() {
+            let conflicts = conflicts(&index)?;
+            rebase.abort()?;
+            return Ok(RebaseOutcome::Conflict(id, conflicts));
+        }
+        // A commit whose changes are already upstream is dropped.
+        match rebase.commit(None, &committer, None) {
+            Ok(id) => last = id,
+            Err(e) if e.code() == ErrorCode::Applied => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    rebase.finish(None)?;
+
+    if last != head {
+        move_head(repo, last, "rebase (finish): returning to HEAD")?;
+    }
+    Ok(RebaseOutcome::Done(last))
+}
+
+/// Rebase HEAD onto UPSTREAM in the repository containing REPO.
+///
+/// The commits reachable from HEAD but not from UPSTREAM are replayed
+/// on top of ONTO, or UPSTREAM if ONTO is nil. The rebase is done in
+/// memory, so that the working tree is only touched once it succeeded.
+///
+/// Return a plist whose :status is `done', with :head the new commit
+/// at HEAD, or `conflict' if replaying a commit conflicted. In that
+/// case nothing was changed, :commit is the commit that could not be
+/// replayed and :conflicts the conflicts as returned by
+/// `git-conflicts'.
+#[lisp_fn(min = "2")]
+pub fn git_rebase(repo: LispObject, upstream: LispStringRef, onto: LispObject) -> LispObject {
+    let upstream = upstream.to_utf8();
+    let onto: Option<LispStringRef> = onto.into();
+    let onto = onto.map(|o| o.to_utf8());
+
+    match with_repository(repo, |repo| rebase(repo, &upstream, onto.as_deref())) {
+        Ok(RebaseOutcome::Done(head)) => list!(QCstatus, Qdone, QChead, oid_to_lisp(head)),
+        Ok(RebaseOutcome::Conflict(commit, conflicts)) => list!(
+            QCstatus,
+            Qconflict,
+            QCcommit,
+            oid_to_lisp(commit),
+            QCconflicts,
+            conflicts_to_lisp(&conflicts)
+        ),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCanalysis, ":analysis");
+    def_lisp_sym!(QCancestor, ":ancestor");
+    def_lisp_sym!(QCcommit, ":commit");
+    def_lisp_sym!(QCconflicts, ":conflicts");
+    def_lisp_sym!(QChead, ":head");
+    def_lisp_sym!(QCours, ":ours");
+    def_lisp_sym!(QCpath, ":path");
+    def_lisp_sym!(QCpreference, ":preference");
+    def_lisp_sym!(QCtheirs, ":theirs");
+    def_lisp_sym!(Qconflict, "conflict");
+    def_lisp_sym!(Qdone, "done");
+    def_lisp_sym!(Qfast_forward, "fast-forward");
+    def_lisp_sym!(Qfast_forward_only, "fast-forward-only");
+    def_lisp_sym!(Qmerged, "merged");
+    def_lisp_sym!(Qno_fast_forward, "no-fast-forward");
+    def_lisp_sym!(Qnormal, "normal");
+    def_lisp_sym!(Qunborn, "unborn");
+    def_lisp_sym!(Qup_to_date, "up-to-date");
+}
+
+include!(concat!(env!("OUT_DIR"), "/merge_exports.rs"));
@@ -0,0 +1,99 @@
+use git2::Reference;
+
+use lisp_macros::lisp_fn;
+
+use emacs_sys::globals::QCname;
+use emacs_sys::globals::QCshorthand;
+use emacs_sys::globals::QCsymbolic;
+use emacs_sys::globals::QCtarget;
+use emacs_sys::globals::Qheads;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qremotes;
+use emacs_sys::globals::Qstringp;
+use emacs_sys::globals::Qtags;
+use emacs_sys::lisp::LispObject;
+
+use crate::convert::bytes_to_lisp;
+use crate::convert::list_from_vec;
+use crate::convert::oid_to_lisp;
+use crate::error::signal_git_error;
+use crate::repository::with_repository;
+
+fn namespace_glob(namespace: LispObject) -> Option<String> {
+    match namespace {
+        Qnil => None,
+        Qheads => Some(String::from("refs/heads/*")),
+        Qremotes => Some(String::from("refs/remotes/*")),
+        Qtags => Some(String::from("refs/tags/*")),
+        _ => match namespace.as_string() {
+            Some(glob) => Some(glob.to_utf8()),
+            None => {
+                wrong_type!(Qstringp, namespace);
+            }
+        },
+    }
+}
+
+fn reference_to_lisp(reference: &Reference) -> LispObject {
+    // Follow symbolic references and tags down to the object they name.
+    let target = reference
+        .resolve()
+        .ok()
+        .and_then(|r| r.peel(git2::ObjectType::Any).ok())
+        .map_or(Qnil, |object| oid_to_lisp(object.id()));
+
+    list!(
+        QCname,
+        bytes_to_lisp(reference.name_bytes()),
+        QCshorthand,
+        bytes_to_lisp(reference.shorthand_bytes()),
+        QCtarget,
+        target,
+        QCsymbolic,
+        reference
+            .symbolic_target_bytes()
+            .map_or(Qnil, bytes_to_lisp)
+    )
+}
+
+/// Return the references of the repository containing REPO.
+///
+/// NAMESPACE restricts the result: `heads' for local branches,
+/// `remotes' for remote-tracking branches, `tags' for tags, or a glob
+/// pattern such as "refs/notes/*". nil returns all references.
+///
+/// Each reference is a plist with the keys :name, the full name,
+/// :shorthand, the name as usually shown, :target, the id of the
+/// object it finally points to, and :symbolic, the name of the
+/// reference it points to if it is symbolic.
+#[lisp_fn(min = "1")]
+pub fn git_references(repo: LispObject, namespace: LispObject) -> LispObject {
+    let glob = namespace_glob(namespace);
+
+    let result = with_repository(repo, |repo| {
+        let references = match &glob {
+            Some(glob) => repo.references_glob(glob)?,
+            None => repo.references()?,
+        };
+        references
+            .map(|reference| reference.map(|r| reference_to_lisp(&r)))
+            .collect::<Result<Vec<_>, _>>()
+    });
+
+    match result {
+        Ok(references) => list_from_vec(references),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCshorthand, ":shorthand");
+    def_lisp_sym!(QCsymbolic, ":symbolic");
+    def_lisp_sym!(QCtarget, ":target");
+    def_lisp_sym!(Qheads, "heads");
+    def_lisp_sym!(Qremotes, "remotes");
+    def_lisp_sym!(Qtags, "tags");
+}
+
+include!(concat!(env!("OUT_DIR"), "/refs_exports.rs"));
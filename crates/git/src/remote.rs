@@ -0,0 +1,297 @@
+use std::cell::RefCell;
+use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
+
+use git2::FetchOptions;
+use git2::PushOptions;
+use git2::RemoteCallbacks;
+use git2::Repository;
+
+use lisp_async::fns::to_owned_userdata;
+use lisp_async::fns::EmacsPipe;
+use lisp_async::fns::PipeData;
+use lisp_async::fns::UserData;
+use lisp_async::pool::WorkerPool;
+use lisp_macros::lisp_fn;
+
+use emacs_sys::bindings::Fdelete_process;
+use emacs_sys::bindings::Ffuncall;
+use emacs_sys::bindings::Fprocess_get;
+use emacs_sys::bindings::Fprocess_put;
+use emacs_sys::globals::QCbytes;
+use emacs_sys::globals::QCcallback;
+use emacs_sys::globals::QCname;
+use emacs_sys::globals::QCobjects;
+use emacs_sys::globals::QCpush_url;
+use emacs_sys::globals::QCtotal_objects;
+use emacs_sys::globals::QCurl;
+use emacs_sys::globals::Qdone;
+use emacs_sys::globals::Qerror;
+use emacs_sys::globals::Qgit__transfer_handler;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qprogress;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::convert::bytes_to_lisp;
+use crate::convert::list_from_vec;
+use crate::convert::string_list;
+use crate::error::error_data;
+use crate::error::signal_git_error;
+use crate::repository::with_repository;
+
+/// How often progress is reported while a transfer is running.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a transfer worker reports to the Lisp thread.
+enum TransferEvent {
+    Progress {
+        objects: usize,
+        total_objects: usize,
+        bytes: usize,
+    },
+    Done,
+    Failed(git2::Error),
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Fetch,
+    Push,
+}
+
+/// Forward progress to REPORT, skipping updates that come in faster
+/// than PROGRESS_INTERVAL, except for the last one.
+fn throttled<'a, F>(report: &'a RefCell<F>) -> impl FnMut(usize, usize, usize) + 'a
+where
+    F: FnMut(TransferEvent),
+{
+    let mut last: Option<Instant> = None;
+    move |objects, total_objects, bytes| {
+        let due = last.map_or(true, |t| t.elapsed() >= PROGRESS_INTERVAL);
+        if due || objects == total_objects {
+            last = Some(Instant::now());
+            (report.borrow_mut())(TransferEvent::Progress {
+                objects,
+                total_objects,
+                bytes,
+            });
+        }
+    }
+}
+
+fn transfer<F>(
+    git_dir: &Path,
+    remote: &str,
+    refspecs: &[String],
+    direction: Direction,
+    report: &RefCell<F>,
+) -> Result<(), git2::Error>
+where
+    F: FnMut(TransferEvent),
+{
+    let repo = Repository::open(git_dir)?;
+    // Accept URLs as well as the names of configured remotes.
+    let mut remote = repo
+        .find_remote(remote)
+        .or_else(|_| repo.remote_anonymous(remote))?;
+
+    let mut callbacks = RemoteCallbacks::new();
+    match direction {
+        Direction::Fetch => {
+            let mut progress = throttled(report);
+            callbacks.transfer_progress(move |stats| {
+                progress(
+                    stats.received_objects(),
+                    stats.total_objects(),
+                    stats.received_bytes(),
+                );
+                true
+            });
+            let mut opts = FetchOptions::new();
+            opts.remote_callbacks(callbacks);
+            remote.fetch(refspecs, Some(&mut opts), None)
+        }
+        Direction::Push => {
+            let mut progress = throttled(report);
+            callbacks.push_transfer_progress(move |current, total, bytes| {
+                progress(current, total, bytes)
+            });
+            callbacks.push_update_reference(|name, status| match status {
+                Some(status) => Err(git2::Error::from_str(&format!(
+                    "Failed to push {}: {}",
+                    name, status
+                ))),
+                None => Ok(()),
+            });
+            let mut opts = PushOptions::new();
+            opts.remote_callbacks(callbacks);
+
+            if refspecs.is_empty() {
+                let head = repo.head()?;
+                let name = head
+                    .name()
+                    .ok_or_else(|| git2::Error::from_str("HEAD is not valid UTF-8"))?;
+                remote.push(&[format!("{}:{}", name, name)], Some(&mut opts))
+            } else {
+                remote.push(refspecs, Some(&mut opts))
+            }
+        }
+    }
+}
+
+/// Run a fetch or push on a worker thread, reporting to CALLBACK
+/// through a lisp-async pipe.
+fn start_transfer(
+    repo: LispObject,
+    remote: LispStringRef,
+    callback: LispObject,
+    refspecs: LispObject,
+    direction: Direction,
+) -> LispObject {
+    let remote = remote.to_utf8();
+    let refspecs = string_list(refspecs);
+    let git_dir = match with_repository(repo, |repo| Ok(repo.path().to_path_buf())) {
+        Ok(git_dir) => git_dir,
+        Err(e) => signal_git_error(e),
+    };
+
+    let (mut pipe, proc) =
+        EmacsPipe::with_handler(Qgit__transfer_handler, String::marker(), UserData::marker());
+    unsafe { Fprocess_put(proc, QCcallback, callback) };
+    let sender = pipe.get_sender();
+
+    WorkerPool::global().execute(move || {
+        let report = RefCell::new(move |event: TransferEvent| {
+            let _ = pipe.message_lisp(&sender, UserData::new(event));
+        });
+        let event = match transfer(&git_dir, &remote, &refspecs, direction, &report) {
+            Ok(()) => TransferEvent::Done,
+            Err(e) => TransferEvent::Failed(e),
+        };
+        (report.borrow_mut())(event);
+    });
+
+    proc
+}
+
+/// Return the remotes configured for the repository containing REPO.
+/// Each is a plist with the keys :name, :url and :push-url, the last
+/// being nil unless a separate URL is configured for pushing.
+#[lisp_fn]
+pub fn git_remotes(repo: LispObject) -> LispObject {
+    let result = with_repository(repo, |repo| {
+        let names = repo.remotes()?;
+        names
+            .iter()
+            .flatten()
+            .map(|name| {
+                let remote = repo.find_remote(name)?;
+                Ok(list!(
+                    QCname,
+                    LispObject::from(name),
+                    QCurl,
+                    bytes_to_lisp(remote.url_bytes()),
+                    QCpush_url,
+                    remote.pushurl_bytes().map_or(Qnil, bytes_to_lisp)
+                ))
+            })
+            .collect::<Result<Vec<_>, git2::Error>>()
+    });
+
+    match result {
+        Ok(remotes) => list_from_vec(remotes),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Fetch from REMOTE into the repository containing REPO on a worker
+/// thread.
+///
+/// REMOTE is the name of a configured remote or a URL. REFSPECS is a
+/// refspec or list of them, nil meaning those configured for REMOTE.
+///
+/// CALLBACK is called with two arguments, a status and data. While
+/// the transfer runs, the status is `progress' and the data a plist
+/// with the keys :objects, :total-objects and :bytes. Once it is over,
+/// the status is `done' with nil, or `error' with the list (CONDITION
+/// MESSAGE CLASS CODE), where CONDITION is the `git-error' the failure
+/// would have been signaled as, and the process returned by this
+/// function is deleted.
+#[lisp_fn(min = "3")]
+pub fn git_fetch(
+    repo: LispObject,
+    remote: LispStringRef,
+    callback: LispObject,
+    refspecs: LispObject,
+) -> LispObject {
+    start_transfer(repo, remote, callback, refspecs, Direction::Fetch)
+}
+
+/// Push to REMOTE from the repository containing REPO on a worker
+/// thread.
+///
+/// REMOTE is the name of a configured remote or a URL. REFSPECS is a
+/// refspec or list of them, nil meaning the branch HEAD refers to.
+/// CALLBACK is called as described for `git-fetch'. Updates refused
+/// by the remote are reported as errors.
+#[lisp_fn(min = "3")]
+pub fn git_push(
+    repo: LispObject,
+    remote: LispStringRef,
+    callback: LispObject,
+    refspecs: LispObject,
+) -> LispObject {
+    start_transfer(repo, remote, callback, refspecs, Direction::Push)
+}
+
+/// Internal handler receiving the progress of `git-fetch' and
+/// `git-push' from their worker.
+#[lisp_fn]
+pub fn git__transfer_handler(proc: LispObject, data: LispObject) -> LispObject {
+    let event: TransferEvent = unsafe { to_owned_userdata(data).unpack() };
+    let callback = unsafe { Fprocess_get(proc, QCcallback) };
+
+    let (status, data, finished) = match event {
+        TransferEvent::Progress {
+            objects,
+            total_objects,
+            bytes,
+        } => (
+            Qprogress,
+            list!(
+                QCobjects,
+                LispObject::from(objects),
+                QCtotal_objects,
+                LispObject::from(total_objects),
+                QCbytes,
+                LispObject::from(bytes)
+            ),
+            false,
+        ),
+        TransferEvent::Done => (Qdone, Qnil, true),
+        TransferEvent::Failed(e) => (Qerror, error_data(&e), true),
+    };
+
+    // The worker has exited after sending the final event.
+    if finished {
+        unsafe { Fdelete_process(proc) };
+    }
+
+    let mut args = [callback, status, data];
+    unsafe { Ffuncall(3, args.as_mut_ptr()) }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCbytes, ":bytes");
+    def_lisp_sym!(QCobjects, ":objects");
+    def_lisp_sym!(QCpush_url, ":push-url");
+    def_lisp_sym!(QCtotal_objects, ":total-objects");
+    def_lisp_sym!(QCurl, ":url");
+    def_lisp_sym!(Qgit__transfer_handler, "git--transfer-handler");
+    def_lisp_sym!(Qprogress, "progress");
+}
+
+include!(concat!(env!("OUT_DIR"), "/remote_exports.rs"));
@@ -1,19 +1,184 @@
+use std::cell::Cell;
+use std::cell::UnsafeCell;
 use std::path::Path;
+use std::path::PathBuf;
 
 use git2::Repository;
 
+use lisp_async::fns::UserData;
 use lisp_macros::lisp_fn;
 
+use emacs_sys::bindings::record_unwind_protect_ptr;
+use emacs_sys::bindings::unbind_to;
+use emacs_sys::bindings::SPECPDL_INDEX;
+use emacs_sys::bindings::XUSER_PTR;
+use emacs_sys::globals::Qnil;
+use emacs_sys::lisp::LispObject;
 use emacs_sys::multibyte::LispStringRef;
 
+use crate::error::signal_git_error;
+
+/// The data behind the repository objects returned by `git-open'. BUSY
+/// is set while a function is using the repository.
+struct RepositoryCell {
+    repo: UnsafeCell<Repository>,
+    busy: Cell<bool>,
+}
+
+extern "C" fn finalize_repository(raw: *mut libc::c_void) {
+    let _repo = unsafe { Box::from_raw(raw as *mut RepositoryCell) };
+}
+
+/// Return the repository held by OBJ if it is a repository object. The
+/// finalizer identifies such objects among other user pointers.
+fn repository_cell(obj: LispObject) -> Option<&'static RepositoryCell> {
+    if !obj.is_user_ptr() {
+        return None;
+    }
+    let finalizer: unsafe extern "C" fn(*mut libc::c_void) = finalize_repository;
+    unsafe {
+        let p = XUSER_PTR(obj);
+        match (*p).finalizer {
+            Some(f) if f as usize == finalizer as usize && !(*p).p.is_null() => {
+                Some(&*((*p).p as *const RepositoryCell))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn repository_busy() -> git2::Error {
+    git2::Error::from_str("Repository is already in use")
+}
+
+extern "C" fn release_repository(busy: *mut libc::c_void) {
+    unsafe { *(busy as *mut bool) = false };
+}
+
+/// Call F with the repository of CELL, which is marked busy meanwhile,
+/// or fail if it is in use already. F can signal while building its
+/// result, which skips any destructor, so the mark is cleared through
+/// the specpdl, which is unwound either way.
+fn with_cell<T, F>(cell: &RepositoryCell, f: F) -> Result<T, git2::Error>
+where
+    F: FnOnce(&mut Repository) -> Result<T, git2::Error>,
+{
+    if cell.busy.replace(true) {
+        return Err(repository_busy());
+    }
+    unsafe {
+        let count = SPECPDL_INDEX();
+        record_unwind_protect_ptr(
+            Some(release_repository),
+            cell.busy.as_ptr() as *mut libc::c_void,
+        );
+        let result = f(&mut *cell.repo.get());
+        unbind_to(count, Qnil);
+        result
+    }
+}
+
+/// Open the repository designated by REPO, either a repository object
+/// or a path at or below the repository. A repository object is opened
+/// anew, for callers that need a repository of their own.
+pub fn open_repository(repo: LispObject) -> Result<Repository, git2::Error> {
+    if let Some(cell) = repository_cell(repo) {
+        let path = with_cell(cell, |repo| Ok(repo.path().to_path_buf()))?;
+        return Repository::open(path);
+    }
+    let path: LispStringRef = repo.into();
+    Repository::discover(Path::new(path.to_utf8().as_str()))
+}
+
+/// Call F with the repository designated by REPO, reusing it if REPO is
+/// a repository object. Errors from both opening and F are handed back
+/// to the caller, so that they are signaled only once the repository
+/// has been released. A repository object already in use, by a caller
+/// further up, is reported as an error rather than shared.
+pub fn with_repository<T, F>(repo: LispObject, f: F) -> Result<T, git2::Error>
+where
+    F: FnOnce(&Repository) -> Result<T, git2::Error>,
+{
+    match repository_cell(repo) {
+        Some(cell) => with_cell(cell, |repo| f(repo)),
+        None => f(&open_repository(repo)?),
+    }
+}
+
+/// Like `with_repository', for operations that need the repository
+/// mutably, such as those on the stash.
+pub fn with_repository_mut<T, F>(repo: LispObject, f: F) -> Result<T, git2::Error>
+where
+    F: FnOnce(&mut Repository) -> Result<T, git2::Error>,
+{
+    match repository_cell(repo) {
+        Some(cell) => with_cell(cell, f),
+        None => f(&mut open_repository(repo)?),
+    }
+}
+
+/// Return PATH relative to the working directory of REPO, which is
+/// how libgit2 expects paths inside the repository. Relative paths are
+/// returned unchanged.
+pub fn relative_path(repo: &Repository, path: &str) -> PathBuf {
+    let path = Path::new(path);
+    match repo.workdir() {
+        Some(workdir) if path.is_absolute() => path
+            .strip_prefix(workdir)
+            .map_or_else(|_| path.to_path_buf(), Path::to_path_buf),
+        _ => path.to_path_buf(),
+    }
+}
+
 #[lisp_fn]
 pub fn git_init(path: LispStringRef) -> LispStringRef {
     match Repository::init(Path::new(path.to_utf8().as_str())) {
         Ok(_repo) => path,
         Err(e) => {
-            error!("Error initializing repository {:?}", e);
+            signal_git_error(e);
         }
     }
 }
 
+/// Open the repository at or above PATH and return an object for it.
+///
+/// Every function taking a REPO argument accepts such an object in
+/// place of a path. The repository then stays open, along with its
+/// object database and index caches, instead of being opened again on
+/// each call. It is closed when the object is garbage collected.
+#[lisp_fn]
+pub fn git_open(path: LispStringRef) -> LispObject {
+    match Repository::discover(Path::new(path.to_utf8().as_str())) {
+        Ok(repo) => {
+            let data = Box::into_raw(Box::new(RepositoryCell {
+                repo: UnsafeCell::new(repo),
+                busy: Cell::new(false),
+            }));
+            UserData::with_data_and_finalizer(data as *mut libc::c_void, Some(finalize_repository))
+                .into()
+        }
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Return t if OBJECT is a repository object returned by `git-open'.
+#[lisp_fn]
+pub fn git_repository_p(object: LispObject) -> bool {
+    repository_cell(object).is_some()
+}
+
+/// Return the working directory of the repository designated by REPO,
+/// or nil if it is bare.
+#[lisp_fn]
+pub fn git_repository_workdir(repo: LispObject) -> LispObject {
+    let result = with_repository(repo, |repo| {
+        Ok(repo.workdir().map(|dir| dir.to_string_lossy().into_owned()))
+    });
+
+    match result {
+        Ok(workdir) => workdir.map_or(Qnil, LispObject::from),
+        Err(e) => signal_git_error(e),
+    }
+}
+
 include!(concat!(env!("OUT_DIR"), "/repository_exports.rs"));
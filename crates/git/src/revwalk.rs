@@ -0,0 +1,238 @@
+use std::cell::RefCell;
+use std::path::Path;
+use std::path::PathBuf;
+
+use git2::Commit;
+use git2::Oid;
+use git2::Repository;
+use git2::Revwalk;
+use git2::Sort;
+
+use lisp_macros::lisp_fn;
+
+use lisp_async::fns::UserData;
+
+use emacs_sys::bindings::XUSER_PTR;
+use emacs_sys::globals::Qgit_revwalk_p;
+use emacs_sys::globals::Qnone;
+use emacs_sys::globals::Qreverse;
+use emacs_sys::globals::Qtime;
+use emacs_sys::globals::Qtopological;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::list::LispConsCircularChecks;
+use emacs_sys::list::LispConsEndChecks;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::convert::commit_to_lisp;
+use crate::convert::list_from_vec;
+use crate::error::signal_git_error;
+use crate::repository::open_repository;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// State behind the user-ptr returned by `git-revwalk'.
+pub struct Walker {
+    // The walk borrows from the repository below. Fields are dropped in
+    // declaration order, so the walk always goes away first.
+    walk: Revwalk<'static>,
+    repo: Box<Repository>,
+    path: Option<PathBuf>,
+}
+
+impl Walker {
+    fn new(repo: Repository) -> Result<Walker, git2::Error> {
+        let repo = Box::new(repo);
+        let walk = repo.revwalk()?;
+        // SAFETY: the repository is boxed and owned by the walker, and
+        // outlives the walk as explained above.
+        let walk = unsafe { std::mem::transmute::<Revwalk<'_>, Revwalk<'static>>(walk) };
+        Ok(Walker {
+            walk,
+            repo,
+            path: None,
+        })
+    }
+
+    fn tree_entry(commit: &Commit, path: &Path) -> Option<Oid> {
+        commit
+            .tree()
+            .ok()
+            .and_then(|tree| tree.get_path(path).ok())
+            .map(|entry| entry.id())
+    }
+
+    // A commit touches the path filter when its version of the path
+    // differs from that of every parent, like `git log -- PATH'.
+    fn touches_path(commit: &Commit, path: &Path) -> bool {
+        let entry = Self::tree_entry(commit, path);
+        if commit.parent_count() == 0 {
+            return entry.is_some();
+        }
+
+        commit
+            .parents()
+            .all(|parent| Self::tree_entry(&parent, path) != entry)
+    }
+
+    fn next_page(&mut self, count: usize) -> Result<Vec<LispObject>, git2::Error> {
+        let mut commits = Vec::with_capacity(count);
+        while commits.len() < count {
+            let oid = match self.walk.next() {
+                Some(oid) => oid?,
+                None => break,
+            };
+
+            let commit = self.repo.find_commit(oid)?;
+            if let Some(ref path) = self.path {
+                if !Self::touches_path(&commit, path) {
+                    continue;
+                }
+            }
+
+            commits.push(commit_to_lisp(&commit));
+        }
+
+        Ok(commits)
+    }
+}
+
+type WalkerCell = RefCell<Walker>;
+
+extern "C" fn finalize_walker(raw: *mut libc::c_void) {
+    let _walker = unsafe { Box::from_raw(raw as *mut WalkerCell) };
+}
+
+/// Return the walker held by OBJ if it is a walker object. As for
+/// repositories, the finalizer tells walkers apart from other user
+/// pointers.
+fn walker_cell(obj: LispObject) -> Option<&'static WalkerCell> {
+    if !obj.is_user_ptr() {
+        return None;
+    }
+    let finalizer: unsafe extern "C" fn(*mut libc::c_void) = finalize_walker;
+    unsafe {
+        let p = XUSER_PTR(obj);
+        match (*p).finalizer {
+            Some(f) if f as usize == finalizer as usize && !(*p).p.is_null() => {
+                Some(&*((*p).p as *const WalkerCell))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn sort_flag(mode: LispObject) -> Sort {
+    match mode {
+        Qtime => Sort::TIME,
+        Qtopological => Sort::TOPOLOGICAL,
+        Qreverse => Sort::REVERSE,
+        Qnone => Sort::NONE,
+        _ => error!("Invalid sort mode, must be 'time, 'topological, 'reverse or 'none"),
+    }
+}
+
+fn parse_sort(sort: LispObject) -> Sort {
+    if sort.is_nil() {
+        Sort::TIME
+    } else if sort.is_symbol() {
+        sort_flag(sort)
+    } else {
+        sort.iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on)
+            .fold(Sort::NONE, |flags, mode| flags | sort_flag(mode))
+    }
+}
+
+/// Start walking the history of the repository containing REPO.
+///
+/// The walk starts at the revision START, which defaults to HEAD. If
+/// PATH is non-nil, only commits changing that file or directory are
+/// returned. SORT is one of the symbols `time', `topological',
+/// `reverse' and `none', or a list of them to combine; it defaults to
+/// `time'.
+///
+/// The result is a walker object to pass to `git-revwalk-next'.
+#[lisp_fn(min = "1")]
+pub fn git_revwalk(
+    repo: LispObject,
+    start: LispObject,
+    path: LispObject,
+    sort: LispObject,
+) -> LispObject {
+    let sort = parse_sort(sort);
+    let start: Option<LispStringRef> = start.into();
+    let path: Option<LispStringRef> = path.into();
+
+    let result = open_repository(repo)
+        .and_then(Walker::new)
+        .and_then(|mut walker| {
+            walker.walk.set_sorting(sort)?;
+            match start {
+                Some(start) => {
+                    let commit = walker
+                        .repo
+                        .revparse_single(&start.to_utf8())?
+                        .peel_to_commit()?;
+                    walker.walk.push(commit.id())?;
+                }
+                None => walker.walk.push_head()?,
+            }
+            walker.path = path.map(|p| PathBuf::from(p.to_utf8()));
+            Ok(walker)
+        });
+
+    match result {
+        Ok(walker) => {
+            let data = Box::into_raw(Box::new(RefCell::new(walker)));
+            UserData::with_data_and_finalizer(data as *mut libc::c_void, Some(finalize_walker))
+                .into()
+        }
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Return t if OBJECT is a walker object returned by `git-revwalk'.
+#[lisp_fn]
+pub fn git_revwalk_p(object: LispObject) -> bool {
+    walker_cell(object).is_some()
+}
+
+/// Return the next COUNT commits of WALKER, a walker object created by
+/// `git-revwalk'. COUNT defaults to 100.
+///
+/// Each commit is a plist with the keys :id, :author, :email, :time,
+/// :summary and :parents. An empty list means the walk is finished.
+#[lisp_fn(min = "1")]
+pub fn git_revwalk_next(walker: LispObject, count: LispObject) -> LispObject {
+    let cell = match walker_cell(walker) {
+        Some(cell) => cell,
+        None => {
+            wrong_type!(Qgit_revwalk_p, walker);
+        }
+    };
+
+    let count = if count.is_nil() {
+        DEFAULT_PAGE_SIZE
+    } else {
+        count.as_natnum_or_error() as usize
+    };
+
+    let result = match cell.try_borrow_mut() {
+        Ok(mut walker) => walker.next_page(count),
+        Err(_) => Err(git2::Error::from_str("Walker is already in use")),
+    };
+    match result {
+        Ok(commits) => list_from_vec(commits),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(Qgit_revwalk_p, "git-revwalk-p");
+    def_lisp_sym!(Qtime, "time");
+    def_lisp_sym!(Qtopological, "topological");
+    def_lisp_sym!(Qreverse, "reverse");
+    def_lisp_sym!(Qnone, "none");
+}
+
+include!(concat!(env!("OUT_DIR"), "/revwalk_exports.rs"));
@@ -0,0 +1,454 @@
+use std::io::Write;
+use std::path::Path;
+
+use git2::ApplyLocation;
+use git2::Diff;
+use git2::DiffOptions;
+use git2::Patch;
+use git2::Repository;
+
+use lisp_macros::lisp_fn;
+
+use emacs_sys::lisp::LispObject;
+use emacs_sys::list::LispConsCircularChecks;
+use emacs_sys::list::LispConsEndChecks;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::diff::make_diff;
+use crate::diff::DiffSide;
+use crate::error::signal_git_error;
+use crate::repository::relative_path;
+use crate::repository::with_repository;
+
+/// The lines picked from one hunk; None stands for the whole hunk.
+type HunkSelection = (usize, Option<Vec<usize>>);
+
+fn parse_selection(hunks: LispObject) -> Vec<HunkSelection> {
+    hunks
+        .iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on)
+        .map(|item| match item.as_cons() {
+            Some(cons) => {
+                let lines = cons
+                    .cdr()
+                    .iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on)
+                    .map(|line| line.as_natnum_or_error() as usize)
+                    .collect();
+                (cons.car().as_natnum_or_error() as usize, Some(lines))
+            }
+            None => (item.as_natnum_or_error() as usize, None),
+        })
+        .collect()
+}
+
+fn write_line(out: &mut Vec<u8>, origin: u8, content: &[u8]) {
+    out.push(origin);
+    out.extend_from_slice(content);
+    if !content.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+}
+
+/// Whether ORIGIN is that of the marker libgit2 puts after a line
+/// lacking its newline at the end of the file.
+fn is_eofnl(origin: char) -> bool {
+    matches!(origin, '=' | '>' | '<')
+}
+
+/// Whether the line LINE_IDX of hunk HUNK_IDX in PATCH lacks its
+/// newline, and added lines picked by LINES follow it.
+fn gains_lines(
+    patch: &Patch,
+    hunk_idx: usize,
+    line_idx: usize,
+    lines: &Option<Vec<usize>>,
+    adds: char,
+) -> Result<bool, git2::Error> {
+    let num_lines = patch.num_lines_in_hunk(hunk_idx)?;
+    if line_idx + 1 >= num_lines || !is_eofnl(patch.line_in_hunk(hunk_idx, line_idx + 1)?.origin())
+    {
+        return Ok(false);
+    }
+    for idx in line_idx + 2..num_lines {
+        let picked = lines.as_ref().map_or(true, |l| l.contains(&idx));
+        if picked && patch.line_in_hunk(hunk_idx, idx)?.origin() == adds {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Build the text of a patch applying the lines of PATCH picked by
+/// SELECTION to the index. PATCH normally goes from the index to the
+/// change being staged; with REVERSE it goes from the change being
+/// unstaged to the index, and the picked lines are undone instead.
+/// Returns None when nothing was picked.
+fn partial_patch(
+    patch: &Patch,
+    selection: &[HunkSelection],
+    reverse: bool,
+) -> Result<Option<Vec<u8>>, git2::Error> {
+    // The origins meaning "add to the index" and "remove from it".
+    let (adds, removes) = if reverse { ('-', '+') } else { ('+', '-') };
+    let mut body = Vec::new();
+    let mut offset: i64 = 0;
+    let mut everything = true;
+
+    for hunk_idx in 0..patch.num_hunks() {
+        let lines = match selection.iter().find(|(idx, _)| *idx == hunk_idx) {
+            Some((_, lines)) => lines,
+            None => {
+                everything = false;
+                continue;
+            }
+        };
+
+        let (hunk, num_lines) = patch.hunk(hunk_idx)?;
+        let mut text = Vec::new();
+        let (mut old_count, mut new_count) = (0i64, 0i64);
+        let mut changed = false;
+        // Whether the previous line of the hunk made it into the patch,
+        // along with its "No newline" marker if it has one.
+        let mut written = false;
+        for line_idx in 0..num_lines {
+            let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+            let picked = lines.as_ref().map_or(true, |l| l.contains(&line_idx));
+            let origin = line.origin();
+            if is_eofnl(origin) {
+                if written {
+                    text.extend_from_slice(b"\\ No newline at end of file\n");
+                }
+                continue;
+            }
+            written = origin == ' ' || origin == removes || picked;
+            if origin == ' ' {
+                write_line(&mut text, b' ', line.content());
+                old_count += 1;
+                new_count += 1;
+            } else if origin == adds {
+                if picked {
+                    write_line(&mut text, b'+', line.content());
+                    new_count += 1;
+                    changed = true;
+                } else {
+                    everything = false;
+                }
+            } else if origin == removes {
+                if picked {
+                    write_line(&mut text, b'-', line.content());
+                    changed = true;
+                } else if gains_lines(patch, hunk_idx, line_idx, lines, adds)? {
+                    // The last line of the file is kept, but lines are
+                    // added after it, so it needs its newline now.
+                    write_line(&mut text, b'-', line.content());
+                    text.extend_from_slice(b"\\ No newline at end of file\n");
+                    write_line(&mut text, b'+', line.content());
+                    new_count += 1;
+                    written = false;
+                    everything = false;
+                } else {
+                    write_line(&mut text, b' ', line.content());
+                    new_count += 1;
+                    everything = false;
+                }
+                old_count += 1;
+            }
+        }
+
+        if !changed {
+            continue;
+        }
+
+        let old_start = i64::from(if reverse {
+            hunk.new_start()
+        } else {
+            hunk.old_start()
+        });
+        // An empty side of a hunk is numbered after the line preceding it.
+        let first_line = if old_count == 0 {
+            old_start + 1
+        } else {
+            old_start
+        };
+        let new_start = first_line + offset - if new_count == 0 { 1 } else { 0 };
+        offset += new_count - old_count;
+
+        write!(
+            body,
+            "@@ -{},{} +{},{} @@\n",
+            old_start, old_count, new_start, new_count
+        )
+        .unwrap();
+        body.extend_from_slice(&text);
+    }
+
+    if body.is_empty() {
+        return Ok(None);
+    }
+
+    let delta = patch.delta();
+    let (target, result) = if reverse {
+        (delta.new_file(), delta.old_file())
+    } else {
+        (delta.old_file(), delta.new_file())
+    };
+    let path = String::from_utf8_lossy(
+        result
+            .path_bytes()
+            .or_else(|| target.path_bytes())
+            .unwrap_or_default(),
+    )
+    .into_owned();
+    let target_exists = target.exists();
+    let result_exists = result.exists() || !everything;
+
+    let mut text = Vec::new();
+    write!(text, "diff --git a/{} b/{}\n", path, path).unwrap();
+    if !target_exists {
+        write!(text, "new file mode {:o}\n", u32::from(result.mode())).unwrap();
+    } else if !result_exists {
+        write!(text, "deleted file mode {:o}\n", u32::from(target.mode())).unwrap();
+    }
+    if target_exists {
+        write!(text, "--- a/{}\n", path).unwrap();
+    } else {
+        text.extend_from_slice(b"--- /dev/null\n");
+    }
+    if result_exists {
+        write!(text, "+++ b/{}\n", path).unwrap();
+    } else {
+        text.extend_from_slice(b"+++ /dev/null\n");
+    }
+    text.extend_from_slice(&body);
+
+    Ok(Some(text))
+}
+
+fn apply_selection(
+    repo: &Repository,
+    path: &Path,
+    selection: &[HunkSelection],
+    context_lines: Option<u32>,
+    reverse: bool,
+) -> Result<bool, git2::Error> {
+    let mut opts = DiffOptions::new();
+    opts.pathspec(path).disable_pathspec_match(true);
+    if let Some(lines) = context_lines {
+        opts.context_lines(lines);
+    }
+
+    // These are the same diffs `git-diff' returns for the unstaged and
+    // staged changes, so that hunk and line indexes match its output.
+    let diff = if reverse {
+        let head = DiffSide::Tree(String::from("HEAD"));
+        make_diff(repo, &head, &DiffSide::Index, &mut opts)?
+    } else {
+        make_diff(repo, &DiffSide::Index, &DiffSide::Worktree, &mut opts)?
+    };
+
+    let patch = match Patch::from_diff(&diff, 0)? {
+        Some(patch) => patch,
+        None => return Ok(false),
+    };
+
+    match partial_patch(&patch, selection, reverse)? {
+        Some(text) => {
+            let partial = Diff::from_buffer(&text)?;
+            repo.apply(&partial, ApplyLocation::Index, None)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn stage_file(repo: &Repository, path: &Path) -> Result<bool, git2::Error> {
+    let mut index = repo.index()?;
+    let exists = repo
+        .workdir()
+        .map_or(false, |workdir| workdir.join(path).exists());
+    if exists {
+        index.add_path(path)?;
+    } else {
+        index.remove_path(path)?;
+    }
+    index.write()?;
+    Ok(true)
+}
+
+fn unstage_file(repo: &Repository, path: &Path) -> Result<bool, git2::Error> {
+    let head = match repo.head() {
+        Ok(head) => Some(head.peel(git2::ObjectType::Commit)?),
+        Err(e) if e.code() == git2::ErrorCode::UnbornBranch => None,
+        Err(e) => return Err(e),
+    };
+    repo.reset_default(head.as_ref(), [path])?;
+    Ok(true)
+}
+
+fn parse_context_lines(context_lines: LispObject) -> Option<u32> {
+    if context_lines.is_nil() {
+        None
+    } else {
+        Some(context_lines.as_natnum_or_error() as u32)
+    }
+}
+
+/// Stage changes to the file PATH in the repository containing REPO.
+///
+/// Without HUNKS, stage the whole file, including its addition or
+/// removal. Otherwise HUNKS is a list selecting parts of the unstaged
+/// diff of PATH, as returned by `git-diff' with its default OLD and
+/// NEW. Each element is either the index of a hunk, to stage all of
+/// it, or a list (HUNK LINE...) of a hunk index followed by the
+/// indexes of the lines in its :lines to stage. CONTEXT-LINES must be
+/// the value passed to `git-diff' when the indexes were computed.
+///
+/// Return non-nil if anything was staged.
+#[lisp_fn(min = "2")]
+pub fn git_stage(
+    repo: LispObject,
+    path: LispStringRef,
+    hunks: LispObject,
+    context_lines: LispObject,
+) -> bool {
+    let selection = parse_selection(hunks);
+    let context_lines = parse_context_lines(context_lines);
+    let path = path.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        let path = relative_path(repo, &path);
+        if selection.is_empty() {
+            stage_file(repo, &path)
+        } else {
+            apply_selection(repo, &path, &selection, context_lines, false)
+        }
+    });
+
+    match result {
+        Ok(staged) => staged,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Unstage changes to the file PATH in the repository containing REPO.
+///
+/// Without HUNKS, reset the index entry of PATH to its state in HEAD.
+/// Otherwise HUNKS selects parts of the staged diff of PATH, as
+/// returned by `git-diff' comparing "HEAD" with `index', in the same
+/// form as for `git-stage'. CONTEXT-LINES must be the value passed to
+/// `git-diff' when the indexes were computed.
+///
+/// Return non-nil if anything was unstaged.
+#[lisp_fn(min = "2")]
+pub fn git_unstage(
+    repo: LispObject,
+    path: LispStringRef,
+    hunks: LispObject,
+    context_lines: LispObject,
+) -> bool {
+    let selection = parse_selection(hunks);
+    let context_lines = parse_context_lines(context_lines);
+    let path = path.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        let path = relative_path(repo, &path);
+        if selection.is_empty() {
+            unstage_file(repo, &path)
+        } else {
+            apply_selection(repo, &path, &selection, context_lines, true)
+        }
+    });
+
+    match result {
+        Ok(unstaged) => unstaged,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The hunks of the patch PARTIAL_PATCH builds from OLD to NEW, or
+    // None if nothing was picked.
+    fn hunks(old: &str, new: &str, selection: &[HunkSelection]) -> Option<String> {
+        let path = Path::new("file");
+        let patch =
+            Patch::from_buffers(old.as_bytes(), Some(path), new.as_bytes(), Some(path), None)
+                .unwrap();
+        let text = partial_patch(&patch, selection, false).unwrap()?;
+        let text = String::from_utf8(text).unwrap();
+        Some(text[text.find("@@").unwrap()..].to_string())
+    }
+
+    #[test]
+    fn whole_hunk() {
+        assert_eq!(
+            hunks("a\nb\n", "a\nc\n", &[(0, None)]).unwrap(),
+            "@@ -1,2 +1,2 @@\n a\n-b\n+c\n"
+        );
+    }
+
+    #[test]
+    fn unpicked_removal_becomes_context() {
+        assert_eq!(
+            hunks("a\nb\n", "a\nc\n", &[(0, Some(vec![2]))]).unwrap(),
+            "@@ -1,2 +1,3 @@\n a\n b\n+c\n"
+        );
+    }
+
+    #[test]
+    fn unpicked_addition_is_dropped() {
+        assert_eq!(
+            hunks("a\nb\n", "a\nc\n", &[(0, Some(vec![1]))]).unwrap(),
+            "@@ -1,2 +1,1 @@\n a\n-b\n"
+        );
+    }
+
+    #[test]
+    fn nothing_picked() {
+        assert_eq!(hunks("a\nb\n", "a\nc\n", &[]), None);
+        assert_eq!(hunks("a\nb\n", "a\nc\n", &[(0, Some(vec![0]))]), None);
+    }
+
+    #[test]
+    fn no_newline_marker_follows_picked_line() {
+        assert_eq!(
+            hunks("a\n", "a\nb", &[(0, None)]).unwrap(),
+            "@@ -1,1 +1,2 @@\n a\n+b\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn no_newline_marker_of_unpicked_line_is_dropped() {
+        assert_eq!(
+            hunks("a\n", "a\nb\nc", &[(0, Some(vec![1]))]).unwrap(),
+            "@@ -1,1 +1,2 @@\n a\n+b\n"
+        );
+    }
+
+    #[test]
+    fn no_newline_marker_kept_on_context() {
+        assert_eq!(
+            hunks("x\nb", "y\nb", &[(0, None)]).unwrap(),
+            "@@ -1,2 +1,2 @@\n-x\n+y\n b\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn no_newline_marker_follows_removal() {
+        assert_eq!(
+            hunks("a\nb", "a\nc", &[(0, Some(vec![1]))]).unwrap(),
+            "@@ -1,2 +1,1 @@\n a\n-b\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn kept_last_line_gains_newline() {
+        assert_eq!(
+            hunks("a\nb", "a\nc\n", &[(0, Some(vec![3]))]).unwrap(),
+            "@@ -1,2 +1,3 @@\n a\n-b\n\\ No newline at end of file\n+b\n+c\n"
+        );
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/stage_exports.rs"));
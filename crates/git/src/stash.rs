@@ -0,0 +1,162 @@
+use git2::ErrorCode;
+use git2::StashApplyOptions;
+use git2::StashFlags;
+
+use lisp_macros::lisp_fn;
+
+use emacs_sys::globals::QCid;
+use emacs_sys::globals::QCindex;
+use emacs_sys::globals::QCmessage;
+use emacs_sys::globals::Qnil;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::convert::list_from_vec;
+use crate::convert::oid_to_lisp;
+use crate::error::signal_git_error;
+use crate::repository::with_repository_mut;
+
+fn stash_index(index: LispObject) -> usize {
+    if index.is_nil() {
+        0
+    } else {
+        index.as_natnum_or_error() as usize
+    }
+}
+
+fn apply_options<'cb>(reinstate_index: bool) -> StashApplyOptions<'cb> {
+    let mut opts = StashApplyOptions::new();
+    if reinstate_index {
+        opts.reinstantiate_index();
+    }
+    opts
+}
+
+/// Stash the local modifications in the repository containing REPO and
+/// reset the working tree and index to HEAD.
+///
+/// MESSAGE describes the stash; nil uses a default one. If
+/// INCLUDE-UNTRACKED is non-nil, untracked files are stashed and
+/// removed as well. If KEEP-INDEX is non-nil, changes already staged
+/// are left in the index.
+///
+/// Return the id of the stash commit, or nil if there was nothing to
+/// stash.
+#[lisp_fn(min = "1")]
+pub fn git_stash_save(
+    repo: LispObject,
+    message: LispObject,
+    include_untracked: bool,
+    keep_index: bool,
+) -> LispObject {
+    let message: Option<LispStringRef> = message.into();
+    let message = message.map(|m| m.to_utf8());
+    let mut flags = StashFlags::DEFAULT;
+    if include_untracked {
+        flags |= StashFlags::INCLUDE_UNTRACKED;
+    }
+    if keep_index {
+        flags |= StashFlags::KEEP_INDEX;
+    }
+
+    let result = with_repository_mut(repo, |repo| {
+        let stasher = repo.signature()?;
+        match repo.stash_save2(&stasher, message.as_deref(), Some(flags)) {
+            Ok(id) => Ok(Some(id)),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    });
+
+    match result {
+        Ok(id) => id.map_or(Qnil, oid_to_lisp),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Apply the stash at INDEX, 0 by default, in the repository containing
+/// REPO, keeping it in the stash list. If REINSTATE-INDEX is non-nil,
+/// changes that were staged when stashing are staged again.
+#[lisp_fn(min = "1")]
+pub fn git_stash_apply(repo: LispObject, index: LispObject, reinstate_index: bool) -> bool {
+    let index = stash_index(index);
+
+    let result = with_repository_mut(repo, |repo| {
+        repo.stash_apply(index, Some(&mut apply_options(reinstate_index)))
+    });
+
+    match result {
+        Ok(()) => true,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Apply the stash at INDEX, 0 by default, in the repository containing
+/// REPO, and remove it from the stash list if that succeeded. See
+/// `git-stash-apply' for REINSTATE-INDEX.
+#[lisp_fn(min = "1")]
+pub fn git_stash_pop(repo: LispObject, index: LispObject, reinstate_index: bool) -> bool {
+    let index = stash_index(index);
+
+    let result = with_repository_mut(repo, |repo| {
+        repo.stash_pop(index, Some(&mut apply_options(reinstate_index)))
+    });
+
+    match result {
+        Ok(()) => true,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Remove the stash at INDEX, 0 by default, from the stash list of the
+/// repository containing REPO.
+#[lisp_fn(min = "1")]
+pub fn git_stash_drop(repo: LispObject, index: LispObject) -> bool {
+    let index = stash_index(index);
+
+    match with_repository_mut(repo, |repo| repo.stash_drop(index)) {
+        Ok(()) => true,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Return the stashes of the repository containing REPO, most recent
+/// first. Each is a plist with the keys :index, :message and :id.
+#[lisp_fn]
+pub fn git_stash_list(repo: LispObject) -> LispObject {
+    let result = with_repository_mut(repo, |repo| {
+        let mut stashes = Vec::new();
+        repo.stash_foreach(|index, message, id| {
+            stashes.push((index, message.to_string(), *id));
+            true
+        })?;
+        Ok(stashes)
+    });
+
+    match result {
+        Ok(stashes) => list_from_vec(
+            stashes
+                .into_iter()
+                .map(|(index, message, id)| {
+                    list!(
+                        QCindex,
+                        LispObject::from(index),
+                        QCmessage,
+                        LispObject::from(message),
+                        QCid,
+                        oid_to_lisp(id)
+                    )
+                })
+                .collect(),
+        ),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCindex, ":index");
+    def_lisp_sym!(QCmessage, ":message");
+}
+
+include!(concat!(env!("OUT_DIR"), "/stash_exports.rs"));
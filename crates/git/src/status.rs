@@ -0,0 +1,120 @@
+use git2::Status;
+use git2::StatusOptions;
+
+use lisp_macros::lisp_fn;
+
+use emacs_sys::globals::Qadded;
+use emacs_sys::globals::Qconflicted;
+use emacs_sys::globals::Qdeleted;
+use emacs_sys::globals::Qignored;
+use emacs_sys::globals::Qmodified;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qrenamed;
+use emacs_sys::globals::Qtypechange;
+use emacs_sys::globals::Quntracked;
+use emacs_sys::lisp::LispObject;
+
+use crate::convert::bytes_to_lisp;
+use crate::convert::list_from_vec;
+use crate::convert::string_list;
+use crate::error::signal_git_error;
+use crate::repository::with_repository;
+
+fn index_state(status: Status) -> LispObject {
+    if status.is_index_new() {
+        Qadded
+    } else if status.is_index_modified() {
+        Qmodified
+    } else if status.is_index_deleted() {
+        Qdeleted
+    } else if status.is_index_renamed() {
+        Qrenamed
+    } else if status.is_index_typechange() {
+        Qtypechange
+    } else {
+        Qnil
+    }
+}
+
+fn worktree_state(status: Status) -> LispObject {
+    if status.is_conflicted() {
+        Qconflicted
+    } else if status.is_ignored() {
+        Qignored
+    } else if status.is_wt_new() {
+        Quntracked
+    } else if status.is_wt_modified() {
+        Qmodified
+    } else if status.is_wt_deleted() {
+        Qdeleted
+    } else if status.is_wt_renamed() {
+        Qrenamed
+    } else if status.is_wt_typechange() {
+        Qtypechange
+    } else {
+        Qnil
+    }
+}
+
+/// Return the status of the files in the repository containing REPO.
+///
+/// The result is a list of entries (PATH INDEX-STATE WORKTREE-STATE).
+/// INDEX-STATE compares the index against HEAD and is one of the
+/// symbols `added', `modified', `deleted', `renamed', `typechange',
+/// or nil when the index is unchanged. WORKTREE-STATE compares the
+/// working tree against the index and takes the same values, except
+/// that new files are reported as `untracked'. Files with merge
+/// conflicts have a WORKTREE-STATE of `conflicted'.
+///
+/// PATHSPEC, if non-nil, is a string or a list of strings limiting the
+/// entries to matching paths. Ignored files are skipped according to
+/// .gitignore unless INCLUDE-IGNORED is non-nil, in which case they
+/// are reported with a WORKTREE-STATE of `ignored'.
+#[lisp_fn(min = "1")]
+pub fn git_status(repo: LispObject, pathspec: LispObject, include_ignored: bool) -> LispObject {
+    let pathspecs = string_list(pathspec);
+    let result = with_repository(repo, |repo| {
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .include_ignored(include_ignored)
+            .renames_head_to_index(true);
+        for spec in pathspecs {
+            opts.pathspec(spec);
+        }
+
+        let statuses = repo.statuses(Some(&mut opts))?;
+        let entries = statuses
+            .iter()
+            .map(|entry| {
+                let status = entry.status();
+                list!(
+                    bytes_to_lisp(entry.path_bytes()),
+                    index_state(status),
+                    worktree_state(status)
+                )
+            })
+            .collect();
+
+        Ok(list_from_vec(entries))
+    });
+
+    match result {
+        Ok(entries) => entries,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(Qadded, "added");
+    def_lisp_sym!(Qmodified, "modified");
+    def_lisp_sym!(Qdeleted, "deleted");
+    def_lisp_sym!(Qrenamed, "renamed");
+    def_lisp_sym!(Qtypechange, "typechange");
+    def_lisp_sym!(Quntracked, "untracked");
+    def_lisp_sym!(Qignored, "ignored");
+    def_lisp_sym!(Qconflicted, "conflicted");
+}
+
+include!(concat!(env!("OUT_DIR"), "/status_exports.rs"));
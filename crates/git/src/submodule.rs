@@ -0,0 +1,163 @@
+use git2::Submodule;
+use git2::SubmoduleIgnore;
+use git2::SubmoduleStatus;
+
+use lisp_macros::lisp_fn;
+
+use emacs_sys::globals::QCbranch;
+use emacs_sys::globals::QChead;
+use emacs_sys::globals::QCindex;
+use emacs_sys::globals::QCname;
+use emacs_sys::globals::QCpath;
+use emacs_sys::globals::QCstatus;
+use emacs_sys::globals::QCurl;
+use emacs_sys::globals::QCworktree;
+use emacs_sys::globals::Qin_config;
+use emacs_sys::globals::Qin_head;
+use emacs_sys::globals::Qin_index;
+use emacs_sys::globals::Qin_worktree;
+use emacs_sys::globals::Qindex_added;
+use emacs_sys::globals::Qindex_deleted;
+use emacs_sys::globals::Qindex_modified;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qworktree_added;
+use emacs_sys::globals::Qworktree_deleted;
+use emacs_sys::globals::Qworktree_index_modified;
+use emacs_sys::globals::Qworktree_modified;
+use emacs_sys::globals::Qworktree_uninitialized;
+use emacs_sys::globals::Qworktree_untracked;
+use emacs_sys::globals::Qworktree_worktree_modified;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::convert::bytes_to_lisp;
+use crate::convert::list_from_vec;
+use crate::convert::oid_to_lisp;
+use crate::error::signal_git_error;
+use crate::repository::with_repository;
+
+fn status_to_lisp(status: SubmoduleStatus) -> LispObject {
+    let flags = [
+        (SubmoduleStatus::IN_HEAD, Qin_head),
+        (SubmoduleStatus::IN_INDEX, Qin_index),
+        (SubmoduleStatus::IN_CONFIG, Qin_config),
+        (SubmoduleStatus::IN_WD, Qin_worktree),
+        (SubmoduleStatus::INDEX_ADDED, Qindex_added),
+        (SubmoduleStatus::INDEX_DELETED, Qindex_deleted),
+        (SubmoduleStatus::INDEX_MODIFIED, Qindex_modified),
+        (SubmoduleStatus::WD_UNINITIALIZED, Qworktree_uninitialized),
+        (SubmoduleStatus::WD_ADDED, Qworktree_added),
+        (SubmoduleStatus::WD_DELETED, Qworktree_deleted),
+        (SubmoduleStatus::WD_MODIFIED, Qworktree_modified),
+        (SubmoduleStatus::WD_INDEX_MODIFIED, Qworktree_index_modified),
+        (SubmoduleStatus::WD_WD_MODIFIED, Qworktree_worktree_modified),
+        (SubmoduleStatus::WD_UNTRACKED, Qworktree_untracked),
+    ];
+    list_from_vec(
+        flags
+            .iter()
+            .filter(|(flag, _)| status.contains(*flag))
+            .map(|(_, symbol)| *symbol)
+            .collect(),
+    )
+}
+
+fn submodule_to_lisp(submodule: &Submodule, status: SubmoduleStatus) -> LispObject {
+    list!(
+        QCname,
+        bytes_to_lisp(submodule.name_bytes()),
+        QCpath,
+        LispObject::from(submodule.path().to_string_lossy().into_owned()),
+        QCurl,
+        submodule.opt_url_bytes().map_or(Qnil, bytes_to_lisp),
+        QCbranch,
+        submodule.branch_bytes().map_or(Qnil, bytes_to_lisp),
+        QChead,
+        submodule.head_id().map_or(Qnil, oid_to_lisp),
+        QCindex,
+        submodule.index_id().map_or(Qnil, oid_to_lisp),
+        QCworktree,
+        submodule.workdir_id().map_or(Qnil, oid_to_lisp),
+        QCstatus,
+        status_to_lisp(status)
+    )
+}
+
+/// List the submodules of the repository containing REPO.
+///
+/// Each is a plist with the keys :name, :path, relative to the top of
+/// the working tree, :url, :branch, the commits recorded for it in
+/// HEAD, the index and its working tree as :head, :index and
+/// :worktree, and :status, a list of symbols such as `in-head',
+/// `index-modified', `worktree-uninitialized' or `worktree-modified'
+/// describing its state.
+#[lisp_fn]
+pub fn git_submodules(repo: LispObject) -> LispObject {
+    let result = with_repository(repo, |repo| {
+        repo.submodules()?
+            .iter()
+            .map(|submodule| {
+                let name = submodule
+                    .name()
+                    .ok_or_else(|| git2::Error::from_str("Submodule name is not valid UTF-8"))?;
+                let status = repo.submodule_status(name, SubmoduleIgnore::Unspecified)?;
+                Ok(submodule_to_lisp(submodule, status))
+            })
+            .collect::<Result<Vec<_>, git2::Error>>()
+    });
+
+    match result {
+        Ok(submodules) => list_from_vec(submodules),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Initialize the submodule called NAME in the repository containing
+/// REPO, copying its URL from .gitmodules to the repository
+/// configuration. An existing configuration is only replaced if
+/// OVERWRITE is non-nil.
+#[lisp_fn(min = "2")]
+pub fn git_submodule_init(repo: LispObject, name: LispStringRef, overwrite: bool) -> bool {
+    let name = name.to_utf8();
+
+    match with_repository(repo, |repo| repo.find_submodule(&name)?.init(overwrite)) {
+        Ok(()) => true,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Update the submodule called NAME in the repository containing REPO,
+/// cloning or fetching it as needed and checking out the commit
+/// recorded for it. If INIT is non-nil, an uninitialized submodule is
+/// initialized first.
+#[lisp_fn(min = "2")]
+pub fn git_submodule_update(repo: LispObject, name: LispStringRef, init: bool) -> bool {
+    let name = name.to_utf8();
+
+    match with_repository(repo, |repo| repo.find_submodule(&name)?.update(init, None)) {
+        Ok(()) => true,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCbranch, ":branch");
+    def_lisp_sym!(QCworktree, ":worktree");
+    def_lisp_sym!(Qin_config, "in-config");
+    def_lisp_sym!(Qin_head, "in-head");
+    def_lisp_sym!(Qin_index, "in-index");
+    def_lisp_sym!(Qin_worktree, "in-worktree");
+    def_lisp_sym!(Qindex_added, "index-added");
+    def_lisp_sym!(Qindex_deleted, "index-deleted");
+    def_lisp_sym!(Qindex_modified, "index-modified");
+    def_lisp_sym!(Qworktree_added, "worktree-added");
+    def_lisp_sym!(Qworktree_deleted, "worktree-deleted");
+    def_lisp_sym!(Qworktree_index_modified, "worktree-index-modified");
+    def_lisp_sym!(Qworktree_modified, "worktree-modified");
+    def_lisp_sym!(Qworktree_uninitialized, "worktree-uninitialized");
+    def_lisp_sym!(Qworktree_untracked, "worktree-untracked");
+    def_lisp_sym!(Qworktree_worktree_modified, "worktree-worktree-modified");
+}
+
+include!(concat!(env!("OUT_DIR"), "/submodule_exports.rs"));
@@ -0,0 +1,81 @@
+use lisp_macros::lisp_fn;
+
+use emacs_sys::lisp::LispObject;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::convert::list_from_vec;
+use crate::convert::oid_to_lisp;
+use crate::error::signal_git_error;
+use crate::repository::with_repository;
+
+/// Create a tag called NAME in the repository containing REPO.
+///
+/// The tag points to the revision TARGET, or HEAD if TARGET is nil.
+/// With MESSAGE, an annotated tag object is created, tagged by the
+/// identity configured for the repository; otherwise the tag is a
+/// lightweight one. An existing tag called NAME is only overwritten if
+/// FORCE is non-nil.
+///
+/// Return the id of the tag object for an annotated tag, and that of
+/// TARGET for a lightweight one.
+#[lisp_fn(min = "2")]
+pub fn git_tag_create(
+    repo: LispObject,
+    name: LispStringRef,
+    target: LispObject,
+    message: LispObject,
+    force: bool,
+) -> LispObject {
+    let target: Option<LispStringRef> = target.into();
+    let target = target.map_or_else(|| String::from("HEAD"), |t| t.to_utf8());
+    let message: Option<LispStringRef> = message.into();
+    let message = message.map(|m| m.to_utf8());
+    let name = name.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        let object = repo.revparse_single(&target)?;
+        match &message {
+            Some(message) => {
+                let tagger = repo.signature()?;
+                repo.tag(&name, &object, &tagger, message, force)
+            }
+            None => repo.tag_lightweight(&name, &object, force),
+        }
+    });
+
+    match result {
+        Ok(id) => oid_to_lisp(id),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Delete the tag called NAME in the repository containing REPO.
+#[lisp_fn]
+pub fn git_tag_delete(repo: LispObject, name: LispStringRef) -> bool {
+    let name = name.to_utf8();
+
+    match with_repository(repo, |repo| repo.tag_delete(&name)) {
+        Ok(()) => true,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Return the names of the tags in the repository containing REPO. If
+/// PATTERN is non-nil, only return those matching that glob pattern.
+#[lisp_fn(min = "1")]
+pub fn git_tag_list(repo: LispObject, pattern: LispObject) -> LispObject {
+    let pattern: Option<LispStringRef> = pattern.into();
+    let pattern = pattern.map(|p| p.to_utf8());
+
+    let result = with_repository(repo, |repo| {
+        let names = repo.tag_names(pattern.as_deref())?;
+        Ok(names.iter().flatten().map(String::from).collect::<Vec<_>>())
+    });
+
+    match result {
+        Ok(names) => list_from_vec(names.into_iter().map(LispObject::from).collect()),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/tag_exports.rs"));
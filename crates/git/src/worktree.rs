@@ -0,0 +1,166 @@
+use std::path::Path;
+
+use git2::WorktreeAddOptions;
+use git2::WorktreeLockStatus;
+use git2::WorktreePruneOptions;
+
+use lisp_macros::lisp_fn;
+
+use emacs_sys::globals::QClocked;
+use emacs_sys::globals::QCname;
+use emacs_sys::globals::QCpath;
+use emacs_sys::globals::QCprunable;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qt;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::multibyte::LispStringRef;
+
+use crate::convert::list_from_vec;
+use crate::error::signal_git_error;
+use crate::repository::with_repository;
+
+/// List the linked worktrees of the repository containing REPO.
+///
+/// Each is a plist with the keys :name, :path, the directory of its
+/// working tree, :locked, nil, t or the reason given when locking it,
+/// and :prunable, non-nil if its working tree is gone and it can be
+/// pruned. The main working tree is not included.
+#[lisp_fn]
+pub fn git_worktrees(repo: LispObject) -> LispObject {
+    let result = with_repository(repo, |repo| {
+        let names = repo.worktrees()?;
+        names
+            .iter()
+            .flatten()
+            .map(|name| {
+                let worktree = repo.find_worktree(name)?;
+                let locked = match worktree.is_locked()? {
+                    WorktreeLockStatus::Unlocked => Qnil,
+                    WorktreeLockStatus::Locked(None) => Qt,
+                    WorktreeLockStatus::Locked(Some(reason)) => LispObject::from(reason),
+                };
+                Ok(list!(
+                    QCname,
+                    LispObject::from(name),
+                    QCpath,
+                    LispObject::from(worktree.path().to_string_lossy().into_owned()),
+                    QClocked,
+                    locked,
+                    QCprunable,
+                    LispObject::from(worktree.is_prunable(None)?)
+                ))
+            })
+            .collect::<Result<Vec<_>, git2::Error>>()
+    });
+
+    match result {
+        Ok(worktrees) => list_from_vec(worktrees),
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Add a linked worktree called NAME at PATH to the repository
+/// containing REPO.
+///
+/// The worktree checks out the existing local branch BRANCH, or a new
+/// branch called NAME starting at HEAD if BRANCH is nil. If LOCK is
+/// non-nil, the worktree is locked right away. Return PATH.
+#[lisp_fn(min = "3")]
+pub fn git_worktree_add(
+    repo: LispObject,
+    name: LispStringRef,
+    path: LispStringRef,
+    branch: LispObject,
+    lock: bool,
+) -> LispStringRef {
+    let branch: Option<LispStringRef> = branch.into();
+    let branch = branch.map(|b| b.to_utf8());
+    let worktree_name = name.to_utf8();
+    let worktree_path = path.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        let reference = match &branch {
+            Some(branch) => Some(
+                repo.find_branch(branch, git2::BranchType::Local)?
+                    .into_reference(),
+            ),
+            None => None,
+        };
+        let mut opts = WorktreeAddOptions::new();
+        opts.lock(lock).reference(reference.as_ref());
+        repo.worktree(&worktree_name, Path::new(&worktree_path), Some(&opts))
+            .map(|_| ())
+    });
+
+    match result {
+        Ok(()) => path,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Lock the worktree called NAME in the repository containing REPO, so
+/// that it is not pruned. REASON, if non-nil, records why.
+#[lisp_fn(min = "2")]
+pub fn git_worktree_lock(repo: LispObject, name: LispStringRef, reason: LispObject) -> bool {
+    let reason: Option<LispStringRef> = reason.into();
+    let reason = reason.map(|r| r.to_utf8());
+    let name = name.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        repo.find_worktree(&name)?.lock(reason.as_deref())
+    });
+
+    match result {
+        Ok(()) => true,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Unlock the worktree called NAME in the repository containing REPO.
+#[lisp_fn]
+pub fn git_worktree_unlock(repo: LispObject, name: LispStringRef) -> bool {
+    let name = name.to_utf8();
+
+    match with_repository(repo, |repo| repo.find_worktree(&name)?.unlock()) {
+        Ok(()) => true,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+/// Prune the worktree called NAME in the repository containing REPO.
+///
+/// Normally only a worktree whose working tree is gone and which is not
+/// locked is pruned. If FORCE is non-nil, the worktree is pruned
+/// regardless, and its working tree is deleted.
+///
+/// Return non-nil if the worktree was pruned.
+#[lisp_fn(min = "2")]
+pub fn git_worktree_prune(repo: LispObject, name: LispStringRef, force: bool) -> bool {
+    let name = name.to_utf8();
+
+    let result = with_repository(repo, |repo| {
+        let worktree = repo.find_worktree(&name)?;
+        let mut opts = WorktreePruneOptions::new();
+        if force {
+            opts.valid(true).locked(true).working_tree(true);
+        }
+        if !worktree.is_prunable(Some(&mut opts))? {
+            return Ok(false);
+        }
+        worktree.prune(Some(&mut opts))?;
+        Ok(true)
+    });
+
+    match result {
+        Ok(pruned) => pruned,
+        Err(e) => signal_git_error(e),
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QClocked, ":locked");
+    def_lisp_sym!(QCprunable, ":prunable");
+}
+
+include!(concat!(env!("OUT_DIR"), "/worktree_exports.rs"));
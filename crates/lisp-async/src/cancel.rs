@@ -0,0 +1,215 @@
+//! Cancellation of the jobs run by `rust_worker`. Every message sent to
+//! a worker is paired with a CancellationToken, which `async-cancel` and
+//! per-message timeouts trip, and which the worker closure can poll to
+//! give up early.
+
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::Instant;
+
+use emacs_sys::bindings::plist_get;
+use emacs_sys::bindings::plist_put;
+use emacs_sys::bindings::Fprocess_plist;
+use emacs_sys::bindings::Fset_process_plist;
+use emacs_sys::bindings::FLOATP;
+use emacs_sys::bindings::XFLOAT_DATA;
+use emacs_sys::globals::QCcontrol;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qnumberp;
+use emacs_sys::lisp::LispObject;
+use lisp_macros::lisp_fn;
+
+use crate::error::WorkerError;
+use crate::fns::UserData;
+
+struct TokenState {
+    cancelled: AtomicBool,
+    timeout: Option<Duration>,
+    deadline: OnceLock<Instant>,
+}
+
+/// Tells a worker closure whether the job it is running is still
+/// wanted. Clones share their state.
+#[derive(Clone)]
+pub struct CancellationToken {
+    state: Arc<TokenState>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::with_optional_timeout(None)
+    }
+
+    /// A token that trips once TIMEOUT has elapsed since the worker
+    /// started the job.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        CancellationToken::with_optional_timeout(Some(timeout))
+    }
+
+    fn with_optional_timeout(timeout: Option<Duration>) -> Self {
+        CancellationToken {
+            state: Arc::new(TokenState {
+                cancelled: AtomicBool::new(false),
+                timeout,
+                deadline: OnceLock::new(),
+            }),
+        }
+    }
+
+    /// Start counting down the timeout, if any.
+    pub fn start(&self) {
+        if let Some(timeout) = self.state.timeout {
+            let _ = self.state.deadline.set(Instant::now() + timeout);
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Acquire) || self.timed_out()
+    }
+
+    /// Whether the job ran out of time, as opposed to being cancelled.
+    pub fn timed_out(&self) -> bool {
+        self.state
+            .deadline
+            .get()
+            .map_or(false, |deadline| Instant::now() >= *deadline)
+    }
+
+    /// The error to report for the job once it is cancelled.
+    pub fn error(&self) -> WorkerError {
+        if self.state.cancelled.load(Ordering::Acquire) {
+            WorkerError::Cancelled
+        } else {
+            WorkerError::Timeout
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
+
+/// The tokens of the jobs sent to a worker, shared between the Lisp
+/// thread and the worker thread. Messages go through the pipe in the
+/// order they were sent, so the tokens are queued in the same order.
+#[derive(Default)]
+pub struct StreamControl {
+    pending: Mutex<VecDeque<CancellationToken>>,
+    current: Mutex<Option<CancellationToken>>,
+}
+
+impl StreamControl {
+    /// Create the control of the stream PROC and record it in the
+    /// process plist.
+    pub fn attach(proc: LispObject) -> Arc<StreamControl> {
+        let control = Arc::new(StreamControl::default());
+        unsafe {
+            let plist = Fprocess_plist(proc);
+            let plist = plist_put(plist, QCcontrol, UserData::new(control.clone()).into());
+            Fset_process_plist(proc, plist);
+        }
+        control
+    }
+
+    /// Return the control of the stream PROC, if it has one.
+    pub fn from_process(proc: LispObject) -> Option<Arc<StreamControl>> {
+        let control = unsafe { plist_get(Fprocess_plist(proc), QCcontrol) };
+        if control.is_user_ptr() {
+            Some(unsafe { control.as_userdata_ref::<Arc<StreamControl>>() }.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Queue the token of a message about to be sent.
+    pub fn push(&self, token: CancellationToken) {
+        self.pending.lock().unwrap().push_back(token);
+    }
+
+    /// Take the token of the message the worker just received, and make
+    /// it the current one.
+    pub fn next(&self) -> CancellationToken {
+        let token = self.pending.lock().unwrap().pop_front().unwrap_or_default();
+        *self.current.lock().unwrap() = Some(token.clone());
+        token
+    }
+
+    /// Called by the worker once the current job is over.
+    pub fn finish(&self) {
+        *self.current.lock().unwrap() = None;
+    }
+
+    /// Cancel the current job and every job still queued. Return the
+    /// number of jobs cancelled.
+    pub fn cancel_all(&self) -> usize {
+        let pending = self.pending.lock().unwrap();
+        let current = self.current.lock().unwrap();
+        for token in pending.iter().chain(current.iter()) {
+            token.cancel();
+        }
+        pending.len() + current.iter().count()
+    }
+}
+
+/// Turn TIMEOUT, nil or a number of seconds, into a token for a new
+/// message.
+pub fn token_for_timeout(timeout: LispObject) -> CancellationToken {
+    if timeout.is_nil() {
+        CancellationToken::new()
+    } else {
+        CancellationToken::with_timeout(duration_from_seconds(timeout))
+    }
+}
+
+/// Turn TIMEOUT, a non-negative number of seconds, into a Duration.
+pub fn duration_from_seconds(timeout: LispObject) -> Duration {
+    let seconds = if unsafe { FLOATP(timeout) } {
+        unsafe { XFLOAT_DATA(timeout) }
+    } else {
+        match timeout.as_fixnum() {
+            Some(n) => n as f64,
+            None => {
+                wrong_type!(Qnumberp, timeout);
+            }
+        }
+    };
+    if !seconds.is_finite() || seconds < 0.0 {
+        error!("Invalid timeout {}", seconds);
+    }
+    Duration::from_secs_f64(seconds)
+}
+
+/// Cancel the job running in the worker of the async stream PROC, along
+/// with the messages sent to it that it has not started yet. Workers
+/// check for cancellation when they can, and the results of cancelled
+/// jobs are discarded. Instead, the error handler of the stream is
+/// called with (cancelled MESSAGE) for each of them, or (timeout
+/// MESSAGE) for jobs that ran out of time, see `async-set-error-handler'.
+///
+/// Return the number of jobs cancelled, or nil if the stream does not
+/// support cancellation.
+#[lisp_fn]
+pub fn async_cancel(proc: LispObject) -> LispObject {
+    match StreamControl::from_process(proc) {
+        Some(control) => LispObject::from(control.cancel_all()),
+        None => Qnil,
+    }
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCcontrol, ":control");
+}
+
+include!(concat!(env!("OUT_DIR"), "/cancel_exports.rs"));
@@ -0,0 +1,127 @@
+//! Structured values exchanged between async workers and Lisp, for
+//! results that are more than a string but do not warrant a user-ptr
+//! of their own.
+
+use libc::c_char;
+
+use emacs_sys::bindings::make_float;
+use emacs_sys::bindings::make_int;
+use emacs_sys::bindings::make_string_from_utf8;
+use emacs_sys::bindings::make_unibyte_string;
+use emacs_sys::bindings::make_vector;
+use emacs_sys::bindings::Fintern;
+use emacs_sys::bindings::ASET;
+use emacs_sys::bindings::FLOATP;
+use emacs_sys::bindings::STRING_MULTIBYTE;
+use emacs_sys::bindings::XFLOAT_DATA;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qt;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::list::LispConsCircularChecks;
+use emacs_sys::list::LispConsEndChecks;
+
+use crate::fns::PipeData;
+use crate::fns::PipeDataOption;
+
+/// A Lisp value built off the Lisp thread. Strings are UTF-8 and become
+/// multibyte strings, while Bytes become unibyte strings.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LispData {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    Symbol(String),
+    List(Vec<LispData>),
+    Vector(Vec<LispData>),
+}
+
+impl PipeData for LispData {
+    fn marker() -> PipeDataOption {
+        PipeDataOption::LISP_DATA
+    }
+}
+
+pub fn lisp_string(s: &str) -> LispObject {
+    unsafe { make_string_from_utf8(s.as_ptr() as *const c_char, s.len() as isize) }
+}
+
+pub fn unibyte_string(bytes: &[u8]) -> LispObject {
+    unsafe { make_unibyte_string(bytes.as_ptr() as *const c_char, bytes.len() as isize) }
+}
+
+impl LispData {
+    /// Build the Lisp value. Must be called on the Lisp thread.
+    pub fn into_lisp(self) -> LispObject {
+        match self {
+            LispData::Nil => Qnil,
+            LispData::Bool(b) => {
+                if b {
+                    Qt
+                } else {
+                    Qnil
+                }
+            }
+            LispData::Int(n) => unsafe { make_int(n) },
+            LispData::Float(f) => unsafe { make_float(f) },
+            LispData::String(s) => lisp_string(&s),
+            LispData::Bytes(bytes) => unibyte_string(&bytes),
+            LispData::Symbol(name) => unsafe { Fintern(lisp_string(&name), Qnil) },
+            LispData::List(items) => items
+                .into_iter()
+                .rev()
+                .fold(Qnil, |list, item| LispObject::cons(item.into_lisp(), list)),
+            LispData::Vector(items) => {
+                let vector = unsafe { make_vector(items.len() as isize, Qnil) };
+                for (i, item) in items.into_iter().enumerate() {
+                    unsafe { ASET(vector, i as isize, item.into_lisp()) };
+                }
+                vector
+            }
+        }
+    }
+
+    /// Read OBJECT, a number, string, symbol, or a list or vector of
+    /// those. Return None for anything else, such as dotted lists.
+    pub fn from_lisp(object: LispObject) -> Option<LispData> {
+        if object.is_nil() {
+            Some(LispData::Nil)
+        } else if object.is_t() {
+            Some(LispData::Bool(true))
+        } else if let Some(n) = object.as_fixnum() {
+            Some(LispData::Int(n))
+        } else if unsafe { FLOATP(object) } {
+            Some(LispData::Float(unsafe { XFLOAT_DATA(object) }))
+        } else if let Some(string) = object.as_string() {
+            if unsafe { STRING_MULTIBYTE(object) } {
+                Some(LispData::String(string.to_utf8()))
+            } else {
+                Some(LispData::Bytes(string.as_slice().to_vec()))
+            }
+        } else if let Some(symbol) = object.as_symbol() {
+            let name: Option<_> = symbol.symbol_name().as_string();
+            name.map(|name| LispData::Symbol(name.to_utf8()))
+        } else if object.is_cons() {
+            let mut items = Vec::new();
+            let mut tails = object.iter_tails(LispConsEndChecks::off, LispConsCircularChecks::on);
+            for tail in &mut tails {
+                items.push(LispData::from_lisp(tail.car())?);
+            }
+            if tails.rest().is_not_nil() {
+                return None;
+            }
+            Some(LispData::List(items))
+        } else if let Some(vector) = object.as_vector() {
+            vector
+                .as_slice()
+                .iter()
+                .map(|item| LispData::from_lisp(*item))
+                .collect::<Option<Vec<_>>>()
+                .map(LispData::Vector)
+        } else {
+            None
+        }
+    }
+}
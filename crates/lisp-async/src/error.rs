@@ -0,0 +1,222 @@
+//! Errors of async workers. Instead of being printed to stderr, or
+//! taking the editor down in the case of a panic, a failure is sent to
+//! Lisp over the stream it occurred in, and handed to the error handler
+//! of that stream.
+
+use std::any::Any;
+use std::fmt;
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
+
+use emacs_sys::bindings::plist_get;
+use emacs_sys::bindings::plist_put;
+use emacs_sys::bindings::Ffuncall;
+use emacs_sys::bindings::Fprocess_plist;
+use emacs_sys::bindings::Fset_process_plist;
+use emacs_sys::globals::QCerror_handler;
+use emacs_sys::globals::Qcancelled;
+use emacs_sys::globals::Qmessage;
+use emacs_sys::globals::Qnil;
+use emacs_sys::lisp::LispObject;
+use lisp_macros::lisp_fn;
+
+use crate::data::lisp_string;
+use crate::data::LispData;
+
+/// A failure of an async worker, delivered to Lisp as a list (TYPE
+/// MESSAGE . DETAILS).
+#[derive(Debug)]
+pub enum WorkerError {
+    /// The worker panicked, with the given message.
+    Panic(String),
+    /// An I/O error ended the stream.
+    Io(std::io::Error),
+    /// The worker reported an error of its own.
+    Failed(String),
+    /// The job was cancelled with `async-cancel'.
+    Cancelled,
+    /// The job ran out of time.
+    Timeout,
+}
+
+impl WorkerError {
+    pub fn from_panic(payload: Box<dyn Any + Send>) -> WorkerError {
+        let message = if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "Worker panicked".to_string()
+        };
+        WorkerError::Panic(message)
+    }
+
+    pub fn into_lisp_data(self) -> LispData {
+        let symbol = |name: &str| LispData::Symbol(name.to_string());
+        match self {
+            WorkerError::Panic(message) => {
+                LispData::List(vec![symbol("panic"), LispData::String(message)])
+            }
+            WorkerError::Io(err) => LispData::List(vec![
+                symbol("io-error"),
+                LispData::String(err.to_string()),
+                symbol(&kind_name(err.kind())),
+            ]),
+            WorkerError::Failed(message) => {
+                LispData::List(vec![symbol("error"), LispData::String(message)])
+            }
+            WorkerError::Cancelled => LispData::List(vec![
+                symbol("cancelled"),
+                LispData::String(self.to_string()),
+            ]),
+            WorkerError::Timeout => {
+                LispData::List(vec![symbol("timeout"), LispData::String(self.to_string())])
+            }
+        }
+    }
+}
+
+impl fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkerError::Panic(message) => write!(f, "Worker panicked: {}", message),
+            WorkerError::Io(err) => write!(f, "{}", err),
+            WorkerError::Failed(message) => write!(f, "{}", message),
+            WorkerError::Cancelled => write!(f, "Job was cancelled"),
+            WorkerError::Timeout => write!(f, "Job timed out"),
+        }
+    }
+}
+
+impl From<std::io::Error> for WorkerError {
+    fn from(err: std::io::Error) -> Self {
+        WorkerError::Io(err)
+    }
+}
+
+// Turn an ErrorKind such as BrokenPipe into broken-pipe.
+fn kind_name(kind: std::io::ErrorKind) -> String {
+    let mut name = String::new();
+    for c in format!("{:?}", kind).chars() {
+        if c.is_ascii_uppercase() {
+            if !name.is_empty() {
+                name.push('-');
+            }
+            name.push(c.to_ascii_lowercase());
+        } else {
+            name.push(c);
+        }
+    }
+    name
+}
+
+/// Run FNC, turning a panic into a WorkerError. Only effective when
+/// panics unwind, see the panic strategy of the workspace profiles.
+pub fn catch_panic<R>(fnc: impl FnOnce() -> R) -> Result<R, WorkerError> {
+    catch_unwind(AssertUnwindSafe(fnc)).map_err(WorkerError::from_panic)
+}
+
+// Called on the lisp thread with an error sent over the stream PROC.
+// Cancellations were asked for, so they are only worth a message when
+// there is a handler to tell.
+pub(crate) fn handle_worker_error(proc: LispObject, error: LispObject) {
+    let handler = unsafe { plist_get(Fprocess_plist(proc), QCerror_handler) };
+    if handler.is_nil() {
+        let kind = error.as_cons().map_or(Qnil, |error| error.car());
+        if kind.eq(Qcancelled) {
+            return;
+        }
+        let mut args = [Qmessage, lisp_string("Async stream error: %S"), error];
+        unsafe { Ffuncall(3, args.as_mut_ptr()) };
+    } else {
+        let mut args = [handler, proc, error];
+        unsafe { Ffuncall(3, args.as_mut_ptr()) };
+    }
+}
+
+/// Make HANDLER the error handler of STREAM. When a worker of STREAM
+/// fails, HANDLER is called with STREAM and a list (TYPE MESSAGE
+/// . DETAILS), where TYPE is `panic' if the worker panicked, `io-error'
+/// if the stream was closed by an I/O error, in which case DETAILS is
+/// a symbol naming the kind of error, `error' for an error reported by
+/// the worker itself, or `cancelled' and `timeout' for jobs that were
+/// cancelled, see `async-cancel'. Without a handler, errors other than
+/// cancellations are displayed with `message'. Return HANDLER.
+#[lisp_fn]
+pub fn async_set_error_handler(stream: LispObject, handler: LispObject) -> LispObject {
+    unsafe {
+        let plist = plist_put(Fprocess_plist(stream), QCerror_handler, handler);
+        Fset_process_plist(stream, plist);
+    }
+    handler
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCerror_handler, ":error-handler");
+    def_lisp_sym!(Qcancelled, "cancelled");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(items: Vec<LispData>) -> LispData {
+        LispData::List(items)
+    }
+
+    fn symbol(name: &str) -> LispData {
+        LispData::Symbol(name.to_string())
+    }
+
+    fn string(s: &str) -> LispData {
+        LispData::String(s.to_string())
+    }
+
+    #[test]
+    fn panics_are_caught() {
+        assert_eq!(catch_panic(|| 1).unwrap(), 1);
+        let err = catch_panic(|| panic!("static")).unwrap_err();
+        assert_eq!(
+            err.into_lisp_data(),
+            list(vec![symbol("panic"), string("static")])
+        );
+        let err = catch_panic(|| panic!("formatted {}", 1)).unwrap_err();
+        assert_eq!(
+            err.into_lisp_data(),
+            list(vec![symbol("panic"), string("formatted 1")])
+        );
+    }
+
+    #[test]
+    fn io_errors_name_their_kind() {
+        let err = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "closed");
+        assert_eq!(
+            WorkerError::from(err).into_lisp_data(),
+            list(vec![
+                symbol("io-error"),
+                string("closed"),
+                symbol("broken-pipe")
+            ])
+        );
+        assert_eq!(kind_name(std::io::ErrorKind::NotFound), "not-found");
+    }
+
+    #[test]
+    fn other_errors() {
+        assert_eq!(
+            WorkerError::Failed("failed".to_string()).into_lisp_data(),
+            list(vec![symbol("error"), string("failed")])
+        );
+        assert_eq!(
+            WorkerError::Cancelled.into_lisp_data(),
+            list(vec![symbol("cancelled"), string("Job was cancelled")])
+        );
+        assert_eq!(
+            WorkerError::Timeout.into_lisp_data(),
+            list(vec![symbol("timeout"), string("Job timed out")])
+        );
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/error_exports.rs"));
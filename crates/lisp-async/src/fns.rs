@@ -5,11 +5,17 @@ use std::io::Read;
 use std::io::Write;
 use std::os::unix::io::FromRawFd;
 use std::os::unix::io::IntoRawFd;
-
-use std::thread;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 
 use crossbeam::channel::Receiver;
+use crossbeam::channel::SendTimeoutError;
 use crossbeam::channel::Sender;
+use crossbeam::channel::TrySendError;
 
 use emacs_sys::bindings::build_string;
 use emacs_sys::bindings::intern_c_string;
@@ -19,31 +25,59 @@ use emacs_sys::bindings::plist_get;
 use emacs_sys::bindings::plist_put;
 use emacs_sys::bindings::Ffuncall;
 use emacs_sys::bindings::Fmake_pipe_process;
+use emacs_sys::bindings::Fprocess_list;
 use emacs_sys::bindings::Fprocess_plist;
 use emacs_sys::bindings::Fset_process_plist;
 use emacs_sys::bindings::Fuser_ptrp;
+use emacs_sys::bindings::STRING_MULTIBYTE;
 use emacs_sys::bindings::XUSER_PTR;
 use emacs_sys::globals::QCcoding;
 use emacs_sys::globals::QCfilter;
+use emacs_sys::globals::QChandler;
 use emacs_sys::globals::QCinchannel;
+use emacs_sys::globals::QCinput;
 use emacs_sys::globals::QCname;
 use emacs_sys::globals::QCoutchannel;
+use emacs_sys::globals::QCoutput;
 use emacs_sys::globals::QCplist;
+use emacs_sys::globals::QCqueued_for_lisp;
+use emacs_sys::globals::QCqueued_for_worker;
+use emacs_sys::globals::QCsend_timeout;
 use emacs_sys::globals::QCtype;
+use emacs_sys::globals::QCwakeup;
+use emacs_sys::globals::QCworker;
+use emacs_sys::globals::Qbytes;
 use emacs_sys::globals::Qcall;
 use emacs_sys::globals::Qdata;
+use emacs_sys::globals::Qjson;
+use emacs_sys::globals::Qlisp_data;
 use emacs_sys::globals::Qnil;
 use emacs_sys::globals::Qraw_text;
 use emacs_sys::globals::Qreturn;
 use emacs_sys::globals::Qstring;
 use emacs_sys::globals::Quser_ptr;
 use emacs_sys::globals::Quser_ptrp;
+use emacs_sys::globals::Qwholenump;
 use emacs_sys::lisp::LispObject;
+use emacs_sys::list::LispConsCircularChecks;
+use emacs_sys::list::LispConsEndChecks;
 use emacs_sys::multibyte::LispStringRef;
 use emacs_sys::process::LispProcessRef;
 use lisp_macros::async_stream;
 use lisp_macros::lisp_fn;
 
+use crate::cancel::duration_from_seconds;
+use crate::cancel::token_for_timeout;
+use crate::cancel::CancellationToken;
+use crate::cancel::StreamControl;
+use crate::data::unibyte_string;
+use crate::data::LispData;
+use crate::error::handle_worker_error;
+use crate::error::WorkerError;
+use crate::json::json_from_lisp;
+use crate::json::json_to_lisp;
+use crate::pool::PooledStream;
+
 #[repr(u32)]
 enum PIPE_PROCESS {
     SUBPROCESS_STDIN = 0,
@@ -64,8 +98,21 @@ pub struct EmacsPipe {
     _in_subp: i32,
     out_subp: i32,
     proc: LispObject,
+    // Shared with the lisp thread, set while a wakeup byte has been
+    // written that the handler has not acted upon yet.
+    wakeup_pending: Option<Arc<AtomicBool>>,
+    // How many milliseconds a worker waits for room in a full channel
+    // before giving up, or 0 to wait for as long as it takes. See
+    // 'async-set-send-timeout'.
+    send_timeout: Option<Arc<AtomicU64>>,
 }
 
+// The number of messages a worker can queue for the lisp thread before
+// it blocks, or 0 for no limit. See 'async-set-channel-capacity'.
+static CHANNEL_CAPACITY: AtomicUsize = AtomicUsize::new(DEFAULT_CHANNEL_CAPACITY);
+
+const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+
 const fn ptr_size() -> usize {
     core::mem::size_of::<*mut String>()
 }
@@ -82,6 +129,9 @@ fn to_data_option(obj: LispObject) -> Option<PipeDataOption> {
     match obj {
         Qstring => Some(String::marker()),
         Quser_ptr => Some(UserData::marker()),
+        Qbytes => Some(Vec::<u8>::marker()),
+        Qjson => Some(serde_json::Value::marker()),
+        Qlisp_data => Some(LispData::marker()),
         _ => None,
     }
 }
@@ -90,6 +140,9 @@ fn from_data_option(option: PipeDataOption) -> LispObject {
     match option {
         PipeDataOption::STRING => Qstring,
         PipeDataOption::USER_DATA => Quser_ptr,
+        PipeDataOption::BYTES => Qbytes,
+        PipeDataOption::JSON => Qjson,
+        PipeDataOption::LISP_DATA => Qlisp_data,
     }
 }
 
@@ -176,6 +229,12 @@ impl Default for UserData {
 pub enum PipeDataOption {
     STRING,
     USER_DATA,
+    // Raw bytes, handed to lisp as a unibyte string.
+    BYTES,
+    // A serde_json::Value, see crate::json.
+    JSON,
+    // A crate::data::LispData.
+    LISP_DATA,
 }
 
 pub trait PipeData {
@@ -194,6 +253,18 @@ impl PipeData for UserData {
     }
 }
 
+impl PipeData for Vec<u8> {
+    fn marker() -> PipeDataOption {
+        PipeDataOption::BYTES
+    }
+}
+
+impl PipeData for serde_json::Value {
+    fn marker() -> PipeDataOption {
+        PipeDataOption::JSON
+    }
+}
+
 impl EmacsPipe {
     pub unsafe fn with_process(process: LispObject) -> EmacsPipe {
         let raw_proc: LispProcessRef = process.into();
@@ -202,12 +273,27 @@ impl EmacsPipe {
         let pi = raw_proc.open_fd[PIPE_PROCESS::READ_FROM_SUBPROCESS as usize];
         let po = raw_proc.open_fd[PIPE_PROCESS::WRITE_TO_SUBPROCESS as usize];
 
+        let wakeup = plist_get(Fprocess_plist(process), QCwakeup);
+        let wakeup_pending = if wakeup.is_user_ptr() {
+            Some(wakeup.as_userdata_ref::<Arc<AtomicBool>>().clone())
+        } else {
+            None
+        };
+        let timeout = plist_get(Fprocess_plist(process), QCsend_timeout);
+        let send_timeout = if timeout.is_user_ptr() {
+            Some(timeout.as_userdata_ref::<Arc<AtomicU64>>().clone())
+        } else {
+            None
+        };
+
         EmacsPipe {
             out_fd: out,
             in_fd: inf,
             _in_subp: pi,
             out_subp: po,
             proc: process,
+            wakeup_pending,
+            send_timeout,
         }
     }
 
@@ -216,13 +302,27 @@ impl EmacsPipe {
         input: PipeDataOption,
         output: PipeDataOption,
     ) -> (EmacsPipe, LispObject) {
-        EmacsPipe::create(handler, input, output)
+        let capacity = CHANNEL_CAPACITY.load(Ordering::Acquire);
+        EmacsPipe::create(handler, input, output, (capacity > 0).then_some(capacity))
+    }
+
+    // Like with_handler, but at most 'capacity' messages can be queued
+    // for the lisp thread, regardless of 'async-set-channel-capacity'.
+    // None lifts the limit.
+    pub fn with_handler_and_capacity(
+        handler: LispObject,
+        input: PipeDataOption,
+        output: PipeDataOption,
+        capacity: Option<usize>,
+    ) -> (EmacsPipe, LispObject) {
+        EmacsPipe::create(handler, input, output, capacity)
     }
 
     fn create(
         handler: LispObject,
         input: PipeDataOption,
         output: PipeDataOption,
+        capacity: Option<usize>,
     ) -> (EmacsPipe, LispObject) {
         let proc = unsafe {
             // We panic here only because it will be a fairly exceptional
@@ -254,9 +354,18 @@ impl EmacsPipe {
         plist = unsafe { plist_put(plist, QCtype, input_type) };
         plist = unsafe { plist_put(plist, Qreturn, output_type) };
 
-        let (s, r): (Sender<String>, Receiver<String>) = crossbeam::channel::unbounded();
+        let (s, r): (Sender<String>, Receiver<String>) = match capacity {
+            // A zero capacity channel would block every worker until
+            // lisp handles its message, so it gets a slot nonetheless.
+            Some(capacity) => crossbeam::channel::bounded(capacity.max(1)),
+            None => crossbeam::channel::unbounded(),
+        };
+        let wakeup_pending = Arc::new(AtomicBool::new(false));
         plist = unsafe { plist_put(plist, QCinchannel, UserData::new(s).into()) };
         plist = unsafe { plist_put(plist, QCoutchannel, UserData::new(r).into()) };
+        plist = unsafe { plist_put(plist, QCwakeup, UserData::new(wakeup_pending).into()) };
+        let send_timeout = Arc::new(AtomicU64::new(0));
+        plist = unsafe { plist_put(plist, QCsend_timeout, UserData::new(send_timeout).into()) };
 
         unsafe { Fset_process_plist(proc, plist) };
         // This should be safe due to the fact that we have created the process
@@ -264,15 +373,30 @@ impl EmacsPipe {
         (unsafe { EmacsPipe::with_process(proc) }, proc)
     }
 
-    fn send(sender: &Sender<String>, s: String) -> std::io::Result<()> {
-        sender.send(s).map_err(|e| {
-            std::io::Error::new(
+    // Queue 's' for the lisp thread. Unless the stream has a send
+    // timeout, this waits for room in a full channel for as long as it
+    // takes, as a result must not be lost because lisp was busy.
+    fn send(&self, sender: &Sender<String>, s: String) -> std::io::Result<()> {
+        let timeout = self
+            .send_timeout
+            .as_ref()
+            .map_or(0, |timeout| timeout.load(Ordering::Acquire));
+        let result = if timeout == 0 {
+            sender
+                .send(s)
+                .map_err(|e| SendTimeoutError::Disconnected(e.into_inner()))
+        } else {
+            sender.send_timeout(s, Duration::from_millis(timeout))
+        };
+        result.map_err(|e| match e {
+            SendTimeoutError::Timeout(_) => std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Timed out waiting for lisp to handle messages",
+            ),
+            SendTimeoutError::Disconnected(s) => std::io::Error::new(
                 std::io::ErrorKind::Other,
-                format!(
-                    "Error while attempting to send message: {:?}",
-                    e.into_inner()
-                ),
-            )
+                format!("Error while attempting to send message: {:?}", s),
+            ),
         })
     }
 
@@ -282,14 +406,35 @@ impl EmacsPipe {
         unsafe { sender_obj.as_userdata_ref::<Sender<String>>().clone() }
     }
 
-    fn recv(&mut self) -> std::io::Result<String> {
+    fn receiver(&self) -> Receiver<String> {
         let plist = unsafe { Fprocess_plist(self.proc) };
         let recv_obj = unsafe { plist_get(plist, QCoutchannel) };
-        let recv: &Receiver<String> = unsafe { recv_obj.as_userdata_ref() };
-        recv.recv()
+        unsafe { recv_obj.as_userdata_ref::<Receiver<String>>().clone() }
+    }
+
+    fn recv(&mut self) -> std::io::Result<String> {
+        self.receiver()
+            .recv()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
     }
 
+    fn try_recv(&mut self) -> Option<String> {
+        self.receiver().try_recv().ok()
+    }
+
+    /// The number of messages sent with 'message_lisp' that the lisp
+    /// thread has not handled yet. Workers producing many results can
+    /// use it to batch them rather than wait for room in the channel.
+    pub fn queued_messages(sender: &Sender<String>) -> usize {
+        sender.len()
+    }
+
+    /// Whether 'message_lisp' would block until the lisp thread handles
+    /// some of the messages queued.
+    pub fn is_channel_full(sender: &Sender<String>) -> bool {
+        sender.is_full()
+    }
+
     // Called from the rust worker thread to send 'content' to the lisp
     // thread, to be processed by the users filter function
     // We don't use internal write due to the fact that in the lisp -> rust
@@ -301,17 +446,76 @@ impl EmacsPipe {
     // to enter in memory addresses for deference. This will also eliminate
     // the issue of 'partial reads' if an address crosses the arbitrary maximum
     // read value of a lisp data pipe (which is 4096 bytes as of this commit)
+    // The handler drains the whole channel whenever it wakes up, so 'r' is
+    // only written if no wakeup is pending already. When the channel is
+    // bounded and full, this blocks until the lisp thread catches up, or
+    // fails with TimedOut after the send timeout of the stream, if any.
     pub fn message_lisp<T: PipeData>(
         &mut self,
         sender: &Sender<String>,
         content: T,
     ) -> std::io::Result<()> {
-        let mut f = unsafe { File::from_raw_fd(self.out_fd) };
         let ptr = Box::into_raw(Box::new(content));
         let bin = ptr as *mut _ as usize;
-        Self::send(sender, bin.to_string())?;
-        f.write("r".as_bytes())?;
-        f.into_raw_fd();
+        self.send(sender, bin.to_string()).map_err(|e| {
+            // Lisp never saw the message, so its content is ours again.
+            drop(unsafe { Box::from_raw(ptr) });
+            e
+        })?;
+        self.wakeup()
+    }
+
+    // Like message_lisp, but never waits for the lisp thread. When the
+    // channel is full, 'content' is dropped and Ok(false) returned.
+    pub fn try_message_lisp<T: PipeData>(
+        &mut self,
+        sender: &Sender<String>,
+        content: T,
+    ) -> std::io::Result<bool> {
+        let ptr = Box::into_raw(Box::new(content));
+        let bin = ptr as *mut _ as usize;
+        if let Err(e) = sender.try_send(bin.to_string()) {
+            // Lisp never saw the message, so its content is ours again.
+            drop(unsafe { Box::from_raw(ptr) });
+            return match e {
+                TrySendError::Full(_) => Ok(false),
+                TrySendError::Disconnected(s) => Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Error while attempting to send message: {:?}", s),
+                )),
+            };
+        }
+        self.wakeup().map(|_| true)
+    }
+
+    // Send 'error' to lisp, where it is handed to the error handler of
+    // the stream instead of its handler. Errors share the channel with
+    // other messages, marked by an 'e' before the pointer, so that they
+    // are delivered in order.
+    pub fn message_error(
+        &mut self,
+        sender: &Sender<String>,
+        error: WorkerError,
+    ) -> std::io::Result<()> {
+        let ptr = Box::into_raw(Box::new(error.into_lisp_data()));
+        let bin = ptr as usize;
+        self.send(sender, format!("e{}", bin)).map_err(|e| {
+            drop(unsafe { Box::from_raw(ptr) });
+            e
+        })?;
+        self.wakeup()
+    }
+
+    fn wakeup(&mut self) -> std::io::Result<()> {
+        let wakeup_needed = match &self.wakeup_pending {
+            Some(pending) => !pending.swap(true, Ordering::AcqRel),
+            None => true,
+        };
+        if wakeup_needed {
+            let mut f = unsafe { File::from_raw_fd(self.out_fd) };
+            f.write("r".as_bytes())?;
+            f.into_raw_fd();
+        }
         Ok(())
     }
 
@@ -330,7 +534,27 @@ impl EmacsPipe {
     // Called from the lisp thread, used to enqueue a message for the
     // rust worker to execute.
     pub fn message_rust_worker<T: PipeData>(&mut self, content: T) -> std::io::Result<()> {
-        self.write_ptr(Box::into_raw(Box::new(content)))
+        self.message_rust_worker_with_token(content, CancellationToken::new())
+    }
+
+    // Like message_rust_worker, with the token the worker will be handed
+    // along with 'content' if the stream supports cancellation.
+    pub fn message_rust_worker_with_token<T: PipeData>(
+        &mut self,
+        content: T,
+        token: CancellationToken,
+    ) -> std::io::Result<()> {
+        if let Some(control) = StreamControl::from_process(self.proc) {
+            control.push(token);
+        }
+        let ptr = Box::into_raw(Box::new(content));
+        match PooledStream::from_process(self.proc) {
+            Some(stream) => {
+                stream.submit(ptr as usize);
+                Ok(())
+            }
+            None => self.write_ptr(ptr),
+        }
     }
 
     pub fn read_next_ptr(&self) -> std::io::Result<usize> {
@@ -358,11 +582,21 @@ impl EmacsPipe {
     }
 
     pub fn close_stream(&mut self) -> std::io::Result<()> {
-        self.internal_write(&nullptr().to_be_bytes())
+        match PooledStream::from_process(self.proc) {
+            Some(stream) => {
+                stream.submit(nullptr());
+                Ok(())
+            }
+            None => self.internal_write(&nullptr().to_be_bytes()),
+        }
+    }
+
+    pub fn process(&self) -> LispObject {
+        self.proc
     }
 }
 
-fn eprint_if_unexpected_error(err: std::io::Error) {
+pub(crate) fn eprint_if_unexpected_error(err: std::io::Error) {
     // If we explicity set "ConnectionAborted" to close the stream
     // we don't want to log, as that was expected.
     if err.kind() != std::io::ErrorKind::ConnectionAborted {
@@ -370,36 +604,49 @@ fn eprint_if_unexpected_error(err: std::io::Error) {
     }
 }
 
+// Create an async stream whose messages are processed by 'fnc' on the
+// shared worker pool, each result being handed to 'handler'.
 pub fn rust_worker<
-    INPUT: Send + PipeData,
-    OUTPUT: Send + PipeData,
+    INPUT: 'static + Send + PipeData,
+    OUTPUT: 'static + Send + PipeData,
     T: 'static + Fn(INPUT) -> OUTPUT + Send,
 >(
     handler: LispObject,
     fnc: T,
+) -> LispObject {
+    rust_worker_with_cancel(handler, move |input, _token: &CancellationToken| fnc(input))
+}
+
+// Like rust_worker, but 'fnc' is also handed the token of the message
+// it is processing, which it can poll to stop early once the message
+// was cancelled with 'async-cancel' or timed out. The result of a
+// cancelled message is dropped, and the error handler of the stream is
+// told instead.
+pub fn rust_worker_with_cancel<
+    INPUT: 'static + Send + PipeData,
+    OUTPUT: 'static + Send + PipeData,
+    T: 'static + Fn(INPUT, &CancellationToken) -> OUTPUT + Send,
+>(
+    handler: LispObject,
+    fnc: T,
 ) -> LispObject {
     let (mut pipe, proc) = EmacsPipe::with_handler(handler, INPUT::marker(), OUTPUT::marker());
     let sender = pipe.get_sender();
-    thread::spawn(move || loop {
-        match pipe.read_pend_message() {
-            Ok(message) => {
-                let result = fnc(message);
-                if let Err(err) = pipe.message_lisp(&sender, result) {
-                    eprint_if_unexpected_error(err);
-                    break;
-                }
-            }
-            Err(err) => {
-                eprint_if_unexpected_error(err);
-                break;
-            }
+    PooledStream::attach(proc, move |message: INPUT, token: &CancellationToken| {
+        if token.is_cancelled() {
+            return pipe.message_error(&sender, token.error());
         }
+        let result = fnc(message, token);
+        if token.is_cancelled() {
+            return pipe.message_error(&sender, token.error());
+        }
+        pipe.message_lisp(&sender, result)
     });
 
     proc
 }
 
-fn make_return_value(ptrval: usize, option: PipeDataOption) -> LispObject {
+pub(crate) fn make_return_value(ptrval: usize, option: PipeDataOption) -> LispObject {
     match option {
         PipeDataOption::STRING => {
             let content = unsafe { *Box::from_raw(ptrval as *mut String) };
@@ -413,6 +660,21 @@ fn make_return_value(ptrval: usize, option: PipeDataOption) -> LispObject {
             let content = unsafe { *Box::from_raw(ptrval as *mut UserData) };
             unsafe { make_user_ptr(content.finalizer, content.data) }
         }
+
+        PipeDataOption::BYTES => {
+            let content = unsafe { *Box::from_raw(ptrval as *mut Vec<u8>) };
+            unibyte_string(&content)
+        }
+
+        PipeDataOption::JSON => {
+            let content = unsafe { *Box::from_raw(ptrval as *mut serde_json::Value) };
+            json_to_lisp(content)
+        }
+
+        PipeDataOption::LISP_DATA => {
+            let content = unsafe { *Box::from_raw(ptrval as *mut LispData) };
+            content.into_lisp()
+        }
     }
 }
 
@@ -435,28 +697,56 @@ pub fn async_handler(proc: LispObject, data: LispStringRef) -> bool {
     // any lisp function. Instead, when data is ready, we write 'r'
     // over the pipe which triggers this function to read the pointer
     // data from a crossbeam channel.
-    for _ in 0..data.len_bytes() {
-        if let Ok(s) = pipe.recv() {
-            let bin = s.parse::<usize>().unwrap();
-            let qtype = unsafe { plist_get(plist, Qreturn) };
-            if let Some(quoted_type) = to_data_option(qtype) {
-                let retval = make_return_value(bin, quoted_type);
-                let mut buffer = vec![orig_handler, proc, retval];
-                unsafe { Ffuncall(3, buffer.as_mut_ptr()) };
-            } else {
-                // This means that someone has mishandled the
-                // process plist and removed :type. Without this,
-                // we cannot safely execute data transfer.
-                wrong_type!(Qdata, qtype);
+    // Writers skip the 'r' while one is pending, so once the flag is
+    // cleared every message in the channel is handled.
+    match pipe.wakeup_pending.clone() {
+        Some(pending) => {
+            pending.store(false, Ordering::Release);
+            // Only the messages queued by now are handled, so that a
+            // worker keeping up with lisp cannot keep it here forever.
+            // Later ones come with a wakeup of their own.
+            for _ in 0..pipe.receiver().len() {
+                match pipe.try_recv() {
+                    Some(s) => handle_message(proc, plist, orig_handler, s),
+                    None => break,
+                }
+            }
+        }
+        None => {
+            for _ in 0..data.len_bytes() {
+                if let Ok(s) = pipe.recv() {
+                    handle_message(proc, plist, orig_handler, s);
+                } else {
+                    error!("Failed to read recv data from pipe");
+                }
             }
-        } else {
-            error!("Failed to read recv data from pipe");
         }
     }
 
     true
 }
 
+fn handle_message(proc: LispObject, plist: LispObject, handler: LispObject, s: String) {
+    if let Some(error) = s.strip_prefix('e') {
+        let bin = error.parse::<usize>().unwrap();
+        let error = make_return_value(bin, PipeDataOption::LISP_DATA);
+        handle_worker_error(proc, error);
+        return;
+    }
+    let bin = s.parse::<usize>().unwrap();
+    let qtype = unsafe { plist_get(plist, Qreturn) };
+    if let Some(quoted_type) = to_data_option(qtype) {
+        let retval = make_return_value(bin, quoted_type);
+        let mut buffer = vec![handler, proc, retval];
+        unsafe { Ffuncall(3, buffer.as_mut_ptr()) };
+    } else {
+        // This means that someone has mishandled the
+        // process plist and removed :type. Without this,
+        // we cannot safely execute data transfer.
+        wrong_type!(Qdata, qtype);
+    }
+}
+
 #[async_stream]
 pub async fn async_echo(s: String) -> String {
     s
@@ -471,11 +761,13 @@ fn internal_send_message(
     pipe: &mut EmacsPipe,
     message: LispObject,
     option: PipeDataOption,
+    token: CancellationToken,
 ) -> bool {
     match option {
         PipeDataOption::STRING => {
             let string: LispStringRef = message.into();
-            pipe.message_rust_worker(string.to_utf8()).is_ok()
+            pipe.message_rust_worker_with_token(string.to_utf8(), token)
+                .is_ok()
         }
         PipeDataOption::USER_DATA => {
             if !is_user_ptr(message) {
@@ -490,18 +782,43 @@ fn internal_send_message(
                 (*data_ptr).finalizer = None;
             };
 
-            pipe.message_rust_worker(ud).is_ok()
+            pipe.message_rust_worker_with_token(ud, token).is_ok()
         }
+        PipeDataOption::BYTES => {
+            let string: LispStringRef = message.into();
+            let bytes = if unsafe { STRING_MULTIBYTE(message) } {
+                string.to_utf8().into_bytes()
+            } else {
+                string.as_slice().to_vec()
+            };
+            pipe.message_rust_worker_with_token(bytes, token).is_ok()
+        }
+        PipeDataOption::JSON => match json_from_lisp(message) {
+            Some(value) => pipe.message_rust_worker_with_token(value, token).is_ok(),
+            None => {
+                wrong_type!(Qjson, message);
+            }
+        },
+        PipeDataOption::LISP_DATA => match LispData::from_lisp(message) {
+            Some(data) => pipe.message_rust_worker_with_token(data, token).is_ok(),
+            None => {
+                wrong_type!(Qlisp_data, message);
+            }
+        },
     }
 }
 
-#[lisp_fn]
-pub fn async_send_message(proc: LispObject, message: LispObject) -> bool {
+/// Send MESSAGE to the worker of the async stream PROC. If TIMEOUT is
+/// non-nil, it is the number of seconds the worker may spend on
+/// MESSAGE before it is cancelled, as with `async-cancel'.
+#[lisp_fn(min = "2")]
+pub fn async_send_message(proc: LispObject, message: LispObject, timeout: LispObject) -> bool {
+    let token = token_for_timeout(timeout);
     let mut pipe = unsafe { EmacsPipe::with_process(proc) };
     let plist = unsafe { Fprocess_plist(proc) };
     let qtype = unsafe { plist_get(plist, QCtype) };
     if let Some(option) = to_data_option(qtype) {
-        internal_send_message(&mut pipe, message, option)
+        internal_send_message(&mut pipe, message, option, token)
     } else {
         // This means that someone has mishandled the
         // process plist and removed :type. Without this,
@@ -516,10 +833,141 @@ pub fn async_close_stream(proc: LispObject) -> bool {
     pipe.close_stream().is_ok()
 }
 
+/// Set the number of messages the worker of an async stream can queue
+/// for Lisp to CAPACITY. A worker with that many messages waiting to be
+/// handled stops until Lisp catches up, which bounds the memory used by
+/// fast producers, see also `async-set-send-timeout'. nil removes the
+/// limit. Streams created before this call keep the capacity they were
+/// created with.
+#[lisp_fn]
+pub fn async_set_channel_capacity(capacity: LispObject) -> LispObject {
+    let value = if capacity.is_nil() {
+        0
+    } else {
+        match capacity.as_natnum() {
+            Some(n) if n > 0 => n as usize,
+            _ => {
+                wrong_type!(Qwholenump, capacity);
+            }
+        }
+    };
+    CHANNEL_CAPACITY.store(value, Ordering::Release);
+    capacity
+}
+
+/// Make the workers of STREAM give up once they waited TIMEOUT seconds
+/// for room in its channel, see `async-set-channel-capacity'. The
+/// stream then fails with a `timed-out' I/O error, see
+/// `async-set-error-handler', and the result that did not fit is lost.
+/// nil, the default, has workers wait for as long as Lisp takes.
+/// Return TIMEOUT.
+#[lisp_fn]
+pub fn async_set_send_timeout(stream: LispObject, timeout: LispObject) -> LispObject {
+    let millis = if timeout.is_nil() {
+        0
+    } else {
+        // 0 means no timeout, so the shortest timeout is a millisecond.
+        u64::try_from(duration_from_seconds(timeout).as_millis())
+            .unwrap_or(u64::MAX)
+            .max(1)
+    };
+    let send_timeout = unsafe { plist_get(Fprocess_plist(stream), QCsend_timeout) };
+    if !send_timeout.is_user_ptr() {
+        error!("Stream has no channel to time out on");
+    }
+    unsafe { send_timeout.as_userdata_ref::<Arc<AtomicU64>>() }.store(millis, Ordering::Release);
+    timeout
+}
+
+/// Return a list of the live async streams, for debugging. Each entry
+/// is (PROC . PLIST), where PLIST has the keys :handler, the function
+/// handed the results of PROC, :input and :output, the types of the
+/// messages sent to its worker and to Lisp, :queued-for-lisp, the
+/// number of results waiting for Lisp, and for streams run on the
+/// worker pool :queued-for-worker, the number of messages it has yet
+/// to process, and :worker, one of `running', `queued', `idle' or
+/// `closed'.
+#[lisp_fn]
+pub fn async_list_streams() -> LispObject {
+    let mut streams = Vec::new();
+    let processes = unsafe { Fprocess_list() };
+    for proc in processes.iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on) {
+        let plist = unsafe { Fprocess_plist(proc) };
+        if !is_user_ptr(unsafe { plist_get(plist, QCinchannel) }) {
+            continue;
+        }
+        let sender = unsafe { EmacsPipe::with_process(proc) }.get_sender();
+        let mut info = vec![
+            QChandler,
+            unsafe { plist_get(plist, Qcall) },
+            QCinput,
+            unsafe { plist_get(plist, QCtype) },
+            QCoutput,
+            unsafe { plist_get(plist, Qreturn) },
+            QCqueued_for_lisp,
+            LispObject::from(EmacsPipe::queued_messages(&sender)),
+        ];
+        if let Some(stream) = PooledStream::from_process(proc) {
+            let (state, queued) = stream.state();
+            info.extend([
+                QCqueued_for_worker,
+                LispObject::from(queued),
+                QCworker,
+                state,
+            ]);
+        }
+        let info = info
+            .into_iter()
+            .rev()
+            .fold(Qnil, |list, item| LispObject::cons(item, list));
+        streams.push(LispObject::cons(proc, info));
+    }
+    streams
+        .into_iter()
+        .rev()
+        .fold(Qnil, |list, stream| LispObject::cons(stream, list))
+}
+
 #[allow(dead_code)]
 fn init_syms() {
     def_lisp_sym!(QCinchannel, "inchannel");
     def_lisp_sym!(QCoutchannel, "outchannel");
+    def_lisp_sym!(QCsend_timeout, ":send-timeout");
+    def_lisp_sym!(QCwakeup, ":wakeup");
+    def_lisp_sym!(QChandler, ":handler");
+    def_lisp_sym!(QCinput, ":input");
+    def_lisp_sym!(QCoutput, ":output");
+    def_lisp_sym!(QCqueued_for_lisp, ":queued-for-lisp");
+    def_lisp_sym!(QCqueued_for_worker, ":queued-for-worker");
+    def_lisp_sym!(Qbytes, "bytes");
+    def_lisp_sym!(Qjson, "json");
+    def_lisp_sym!(Qlisp_data, "lisp-data");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_options_round_trip() {
+        for symbol in [Qstring, Quser_ptr, Qbytes, Qjson, Qlisp_data] {
+            let option = to_data_option(symbol).unwrap();
+            assert!(from_data_option(option) == symbol);
+        }
+        assert!(to_data_option(Qnil).is_none());
+    }
+
+    #[test]
+    fn payload_markers() {
+        let markers = [
+            (Vec::<u8>::marker(), Qbytes),
+            (serde_json::Value::marker(), Qjson),
+            (LispData::marker(), Qlisp_data),
+        ];
+        for (marker, symbol) in markers {
+            assert!(from_data_option(marker) == symbol);
+        }
+    }
 }
 
 include!(concat!(env!("OUT_DIR"), "/fns_exports.rs"));
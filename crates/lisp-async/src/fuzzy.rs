@@ -0,0 +1,477 @@
+//! Fuzzy matching of completion candidates, scored the way fzf does
+//! with nucleo's matcher. Candidates are split into chunks shared with
+//! the worker pool, and the positions of the matched characters come
+//! along with each score, for highlighting.
+
+use std::cmp::Reverse;
+use std::panic::catch_unwind;
+use std::panic::resume_unwind;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crossbeam::channel::Sender;
+
+use nucleo_matcher::pattern::CaseMatching;
+use nucleo_matcher::pattern::Normalization;
+use nucleo_matcher::pattern::Pattern;
+use nucleo_matcher::Config;
+use nucleo_matcher::Matcher;
+use nucleo_matcher::Utf32Str;
+
+use emacs_sys::bindings::make_vector;
+use emacs_sys::bindings::plist_get;
+use emacs_sys::bindings::plist_put;
+use emacs_sys::bindings::Ffuncall;
+use emacs_sys::bindings::Fplist_member;
+use emacs_sys::bindings::Fprocess_plist;
+use emacs_sys::bindings::Fset_process_plist;
+use emacs_sys::bindings::AREF;
+use emacs_sys::bindings::ASET;
+use emacs_sys::globals::QCcandidates;
+use emacs_sys::globals::QCcase_fold;
+use emacs_sys::globals::QCfuzzy_handler;
+use emacs_sys::globals::QClimit;
+use emacs_sys::globals::QCpaths;
+use emacs_sys::globals::Qasync__fuzzy_handler;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qsmart;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::list::LispConsCircularChecks;
+use emacs_sys::list::LispConsEndChecks;
+use emacs_sys::multibyte::LispStringRef;
+use lisp_macros::lisp_fn;
+
+use crate::cancel::CancellationToken;
+use crate::data::LispData;
+use crate::fns::rust_worker_with_cancel;
+use crate::pool::WorkerPool;
+
+// Below this many candidates, matching stays on a single thread.
+const MIN_CANDIDATES_PER_THREAD: usize = 4096;
+
+// How often each thread checks whether its query was cancelled.
+const CANCEL_CHECK_INTERVAL: usize = 1024;
+
+#[derive(Clone, Copy)]
+struct FuzzyOptions {
+    case_matching: CaseMatching,
+    paths: bool,
+    limit: Option<usize>,
+}
+
+impl FuzzyOptions {
+    fn from_plist(options: LispObject) -> FuzzyOptions {
+        let case_fold = unsafe { plist_get(options, QCcase_fold) };
+        let paths = unsafe { plist_get(options, QCpaths) };
+        let limit = unsafe { plist_get(options, QClimit) };
+        // Smart case is the default, as in fzf.
+        let case_fold_given = unsafe { Fplist_member(options, QCcase_fold) }.is_not_nil();
+        let case_matching = if case_fold.eq(Qsmart) || !case_fold_given {
+            CaseMatching::Smart
+        } else if case_fold.is_nil() {
+            CaseMatching::Respect
+        } else {
+            CaseMatching::Ignore
+        };
+        FuzzyOptions {
+            case_matching,
+            paths: paths.is_not_nil(),
+            limit: if limit.is_nil() {
+                None
+            } else {
+                Some(limit.as_natnum_or_error() as usize)
+            },
+        }
+    }
+
+    fn matcher(&self) -> Matcher {
+        let mut config = Config::DEFAULT;
+        if self.paths {
+            config.set_match_paths();
+        }
+        Matcher::new(config)
+    }
+}
+
+struct Scored {
+    index: usize,
+    score: u32,
+    positions: Vec<u32>,
+}
+
+fn match_chunk(
+    pattern: &Pattern,
+    candidates: &[String],
+    offset: usize,
+    options: &FuzzyOptions,
+    token: Option<&CancellationToken>,
+) -> Vec<Scored> {
+    let mut matcher = options.matcher();
+    let mut buffer = Vec::new();
+    let mut scored = Vec::new();
+    for (i, candidate) in candidates.iter().enumerate() {
+        if i % CANCEL_CHECK_INTERVAL == 0 && token.map_or(false, |t| t.is_cancelled()) {
+            break;
+        }
+        let mut positions = Vec::new();
+        let haystack = Utf32Str::new(candidate, &mut buffer);
+        if let Some(score) = pattern.indices(haystack, &mut matcher, &mut positions) {
+            positions.sort_unstable();
+            positions.dedup();
+            scored.push(Scored {
+                index: offset + i,
+                score,
+                positions,
+            });
+        }
+    }
+    scored
+}
+
+type ChunkResult = std::thread::Result<Vec<Scored>>;
+
+// A query whose chunks are claimed in turn by the thread that asked for
+// it and by helpers on the pool.
+struct Query {
+    pattern: Pattern,
+    candidates: Arc<Vec<String>>,
+    options: FuzzyOptions,
+    token: Option<CancellationToken>,
+    chunk_size: usize,
+    chunks: usize,
+    next: AtomicUsize,
+}
+
+impl Query {
+    // Match chunks until none are left, sending each result to RESULTS.
+    fn run_chunks(&self, results: &Sender<ChunkResult>) {
+        loop {
+            let chunk = self.next.fetch_add(1, Ordering::AcqRel);
+            if chunk >= self.chunks {
+                break;
+            }
+            let start = chunk * self.chunk_size;
+            let end = (start + self.chunk_size).min(self.candidates.len());
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                match_chunk(
+                    &self.pattern,
+                    &self.candidates[start..end],
+                    start,
+                    &self.options,
+                    self.token.as_ref(),
+                )
+            }));
+            // The receiver only goes away once every chunk was received.
+            let _ = results.send(result);
+        }
+    }
+}
+
+// Score CANDIDATES against PATTERN, and return those that match, best
+// first. Ties go to the shorter candidate, then the earlier one.
+//
+// The calling thread matches chunks along with helpers started on the
+// pool, and only waits for chunks a helper already started on. This
+// keeps a query going when the pool is busy, or is running the query
+// itself, rather than having it wait for threads that are not free.
+fn fuzzy_match(
+    pattern: &str,
+    candidates: Arc<Vec<String>>,
+    options: FuzzyOptions,
+    token: Option<CancellationToken>,
+) -> Vec<Scored> {
+    let pool = WorkerPool::global();
+    let chunks = pool
+        .max_threads()
+        .min(candidates.len() / MIN_CANDIDATES_PER_THREAD)
+        .max(1);
+    let chunk_size = candidates.len().div_ceil(chunks).max(1);
+    let query = Arc::new(Query {
+        pattern: Pattern::parse(pattern, options.case_matching, Normalization::Smart),
+        candidates,
+        options,
+        token,
+        chunk_size,
+        chunks,
+        next: AtomicUsize::new(0),
+    });
+
+    let (results, received) = crossbeam::channel::unbounded();
+    for _ in 1..chunks {
+        let (query, results) = (query.clone(), results.clone());
+        pool.execute(move || query.run_chunks(&results));
+    }
+    query.run_chunks(&results);
+
+    let mut scored = Vec::new();
+    for _ in 0..chunks {
+        match received.recv().unwrap() {
+            Ok(chunk) => scored.extend(chunk),
+            Err(payload) => resume_unwind(payload),
+        }
+    }
+    let candidates = &query.candidates;
+    scored.sort_by_key(|s| (Reverse(s.score), candidates[s.index].len(), s.index));
+    if let Some(limit) = options.limit {
+        scored.truncate(limit);
+    }
+    scored
+}
+
+fn positions_data(positions: Vec<u32>) -> LispData {
+    LispData::List(
+        positions
+            .into_iter()
+            .map(|p| LispData::Int(p as i64))
+            .collect(),
+    )
+}
+
+// The (INDEX SCORE POSITIONS) entry of a match, sent back to Lisp.
+fn scored_data(scored: Scored) -> LispData {
+    LispData::List(vec![
+        LispData::Int(scored.index as i64),
+        LispData::Int(scored.score as i64),
+        positions_data(scored.positions),
+    ])
+}
+
+fn candidate_strings(candidates: LispObject) -> (Vec<LispObject>, Arc<Vec<String>>) {
+    let (objects, strings) = candidates
+        .iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on)
+        .map(|candidate| {
+            let string: LispStringRef = candidate.into();
+            (candidate, string.to_utf8())
+        })
+        .unzip();
+    (objects, Arc::new(strings))
+}
+
+/// Fuzzy match PATTERN against CANDIDATES, a list of strings, and return
+/// the candidates that match, best first, as a list of (CANDIDATE SCORE
+/// POSITIONS). POSITIONS are the indices of the characters of CANDIDATE
+/// that PATTERN matched, in increasing order, for highlighting.
+///
+/// PATTERN uses the syntax of fzf: words separated by spaces must all
+/// match, and each word is fuzzy matched unless it starts with ' for an
+/// exact match, ^ to match a prefix, ! to exclude matches, or ends with
+/// $ to match a suffix.
+///
+/// OPTIONS is a plist. `:case-fold' is `smart' by default, to ignore case
+/// only when a word of PATTERN is all lower case, while t always ignores
+/// it and nil respects it. `:paths' non-nil scores CANDIDATES as file
+/// names, favoring matches at the start of path components. `:limit'
+/// is the maximum number of matches to return.
+#[lisp_fn(min = "2")]
+pub fn async_fuzzy_match_sync(
+    pattern: LispStringRef,
+    candidates: LispObject,
+    options: LispObject,
+) -> LispObject {
+    let options = FuzzyOptions::from_plist(options);
+    let (objects, strings) = candidate_strings(candidates);
+    fuzzy_match(&pattern.to_utf8(), strings, options, None)
+        .into_iter()
+        .rev()
+        .fold(Qnil, |list, scored| {
+            let entry = list!(
+                objects[scored.index],
+                LispObject::from(scored.score as usize),
+                positions_data(scored.positions).into_lisp()
+            );
+            LispObject::cons(entry, list)
+        })
+}
+
+/// Return a fuzzy matcher over CANDIDATES, a list of strings, which are
+/// copied once so that they can be matched off the Lisp thread. Each
+/// pattern sent to the matcher with `async-send-message' is matched
+/// against CANDIDATES as with `async-fuzzy-match-sync', and HANDLER is
+/// called with the matcher and a list (PATTERN MATCHES), where MATCHES
+/// are the (CANDIDATE SCORE POSITIONS) entries. As there, CANDIDATE is
+/// the element of CANDIDATES itself rather than a copy.
+///
+/// Patterns are matched in the order they are sent. When a new pattern
+/// makes the previous ones obsolete, `async-cancel' drops them, so that
+/// HANDLER only sees the latest. OPTIONS are those of
+/// `async-fuzzy-match-sync'.
+#[lisp_fn(min = "2")]
+pub fn async_fuzzy_matcher(
+    candidates: LispObject,
+    handler: LispObject,
+    options: LispObject,
+) -> LispObject {
+    let options = FuzzyOptions::from_plist(options);
+    let (objects, strings) = candidate_strings(candidates);
+    // Matches come back with the index of their candidate, which
+    // `async--fuzzy-handler' looks up in this vector.
+    let vector = unsafe { make_vector(objects.len() as isize, Qnil) };
+    for (i, object) in objects.into_iter().enumerate() {
+        unsafe { ASET(vector, i as isize, object) };
+    }
+
+    let proc = rust_worker_with_cancel(Qasync__fuzzy_handler, move |pattern: String, token| {
+        let matches = fuzzy_match(&pattern, strings.clone(), options, Some(token.clone()))
+            .into_iter()
+            .map(scored_data)
+            .collect();
+        LispData::List(vec![LispData::String(pattern), LispData::List(matches)])
+    });
+    unsafe {
+        let plist = plist_put(Fprocess_plist(proc), QCfuzzy_handler, handler);
+        let plist = plist_put(plist, QCcandidates, vector);
+        Fset_process_plist(proc, plist);
+    }
+    proc
+}
+
+/// Hand the matches DATA of the fuzzy matcher PROC to its handler, once
+/// the index of each match is replaced with its candidate.
+#[lisp_fn]
+pub fn async__fuzzy_handler(proc: LispObject, data: LispObject) -> bool {
+    let plist = unsafe { Fprocess_plist(proc) };
+    let handler = unsafe { plist_get(plist, QCfuzzy_handler) };
+    let candidates = unsafe { plist_get(plist, QCcandidates) };
+    let matches = data.force_cons().cdr().force_cons().car();
+    for entry in matches.iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on) {
+        let entry = entry.force_cons();
+        let index = entry.car().as_natnum_or_error() as isize;
+        entry.set_car(unsafe { AREF(candidates, index) });
+    }
+    let mut args = [handler, proc, data];
+    unsafe { Ffuncall(3, args.as_mut_ptr()) };
+    true
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCcandidates, ":candidates");
+    def_lisp_sym!(QCcase_fold, ":case-fold");
+    def_lisp_sym!(QCfuzzy_handler, ":fuzzy-handler");
+    def_lisp_sym!(QClimit, ":limit");
+    def_lisp_sym!(QCpaths, ":paths");
+    def_lisp_sym!(Qasync__fuzzy_handler, "async--fuzzy-handler");
+    def_lisp_sym!(Qsmart, "smart");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(case_matching: CaseMatching, limit: Option<usize>) -> FuzzyOptions {
+        FuzzyOptions {
+            case_matching,
+            paths: false,
+            limit,
+        }
+    }
+
+    fn candidates(names: &[&str]) -> Arc<Vec<String>> {
+        Arc::new(names.iter().map(|name| name.to_string()).collect())
+    }
+
+    fn matched(pattern: &str, names: &[&str], options: FuzzyOptions) -> Vec<usize> {
+        fuzzy_match(pattern, candidates(names), options, None)
+            .into_iter()
+            .map(|scored| scored.index)
+            .collect()
+    }
+
+    #[test]
+    fn best_first_and_shorter_on_ties() {
+        let names = ["xaxbxc", "abcd", "nothing", "abc"];
+        let smart = options(CaseMatching::Smart, None);
+        assert_eq!(matched("abc", &names, smart), vec![3, 1, 0]);
+    }
+
+    #[test]
+    fn positions_are_sorted_char_indices() {
+        let scored = fuzzy_match(
+            "abc",
+            candidates(&["éaébéc"]),
+            options(CaseMatching::Smart, None),
+            None,
+        );
+        assert_eq!(scored.len(), 1);
+        assert_eq!(scored[0].positions, vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn case_matching() {
+        let names = ["Foo", "foo"];
+        assert_eq!(
+            matched("Foo", &names, options(CaseMatching::Smart, None)),
+            vec![0]
+        );
+        assert_eq!(
+            matched("foo", &names, options(CaseMatching::Smart, None)).len(),
+            2
+        );
+        assert_eq!(
+            matched("foo", &names, options(CaseMatching::Respect, None)),
+            vec![1]
+        );
+        assert_eq!(
+            matched("FOO", &names, options(CaseMatching::Ignore, None)).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn limit() {
+        let names = ["abc", "abcd", "abcde"];
+        assert_eq!(
+            matched("abc", &names, options(CaseMatching::Smart, Some(2))),
+            vec![0, 1]
+        );
+    }
+
+    #[test]
+    fn chunks_match_like_a_single_pass() {
+        let names: Vec<String> = (0..4 * MIN_CANDIDATES_PER_THREAD)
+            .map(|i| format!("file{}.rs", i))
+            .collect();
+        let all = Arc::new(names);
+        let smart = options(CaseMatching::Smart, None);
+        let pattern = Pattern::parse("f12rs", CaseMatching::Smart, Normalization::Smart);
+        let mut expected = match_chunk(&pattern, &all, 0, &smart, None);
+        expected.sort_by_key(|s| (Reverse(s.score), all[s.index].len(), s.index));
+        let scored = fuzzy_match("f12rs", all.clone(), smart, None);
+        let indices = |scored: &[Scored]| scored.iter().map(|s| s.index).collect::<Vec<_>>();
+        assert_eq!(indices(&scored), indices(&expected));
+    }
+
+    #[test]
+    fn cancelled_query_matches_nothing() {
+        let token = CancellationToken::new();
+        token.cancel();
+        let scored = fuzzy_match(
+            "abc",
+            candidates(&["abc"]),
+            options(CaseMatching::Smart, None),
+            Some(token),
+        );
+        assert!(scored.is_empty());
+    }
+
+    #[test]
+    fn scored_data() {
+        let scored = Scored {
+            index: 2,
+            score: 40,
+            positions: vec![0, 3],
+        };
+        assert_eq!(
+            super::scored_data(scored),
+            LispData::List(vec![
+                LispData::Int(2),
+                LispData::Int(40),
+                LispData::List(vec![LispData::Int(0), LispData::Int(3)]),
+            ])
+        );
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/fuzzy_exports.rs"));
@@ -0,0 +1,181 @@
+//! JSON payloads of async streams. Values are converted with the
+//! defaults of `json-parse-string' and `json-serialize': objects are
+//! hash tables, arrays are vectors, and null and false are the keywords
+//! `:null' and `:false'. As with `json-serialize', nil is an empty
+//! object, and alists and plists are objects too.
+
+use serde_json::Map;
+use serde_json::Number;
+use serde_json::Value;
+
+use emacs_sys::bindings::check_integer_range;
+use emacs_sys::bindings::hash_unused_entry_key_p;
+use emacs_sys::bindings::intmax_t;
+use emacs_sys::bindings::make_float;
+use emacs_sys::bindings::make_int;
+use emacs_sys::bindings::make_uint;
+use emacs_sys::bindings::make_vector;
+use emacs_sys::bindings::Fmake_hash_table;
+use emacs_sys::bindings::Fputhash;
+use emacs_sys::bindings::ASET;
+use emacs_sys::bindings::FLOATP;
+use emacs_sys::bindings::HASH_KEY;
+use emacs_sys::bindings::HASH_TABLE_P;
+use emacs_sys::bindings::HASH_TABLE_SIZE;
+use emacs_sys::bindings::HASH_VALUE;
+use emacs_sys::bindings::INTEGERP;
+use emacs_sys::bindings::XFLOAT_DATA;
+use emacs_sys::bindings::XHASH_TABLE;
+use emacs_sys::globals::QCfalse;
+use emacs_sys::globals::QCnull;
+use emacs_sys::globals::QCsize;
+use emacs_sys::globals::QCtest;
+use emacs_sys::globals::Qequal;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qt;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::list::LispConsCircularChecks;
+use emacs_sys::list::LispConsEndChecks;
+
+use crate::data::lisp_string;
+
+/// Build the Lisp value of VALUE. Must be called on the Lisp thread.
+pub fn json_to_lisp(value: Value) -> LispObject {
+    match value {
+        Value::Null => QCnull,
+        Value::Bool(true) => Qt,
+        Value::Bool(false) => QCfalse,
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                unsafe { make_int(i) }
+            } else if let Some(u) = n.as_u64() {
+                unsafe { make_uint(u) }
+            } else {
+                unsafe { make_float(n.as_f64().unwrap_or(f64::NAN)) }
+            }
+        }
+        Value::String(s) => lisp_string(&s),
+        Value::Array(items) => {
+            let vector = unsafe { make_vector(items.len() as isize, Qnil) };
+            for (i, item) in items.into_iter().enumerate() {
+                unsafe { ASET(vector, i as isize, json_to_lisp(item)) };
+            }
+            vector
+        }
+        Value::Object(map) => {
+            let mut args = [QCtest, Qequal, QCsize, LispObject::from(map.len())];
+            let table = unsafe { Fmake_hash_table(args.len() as isize, args.as_mut_ptr()) };
+            for (key, value) in map {
+                unsafe { Fputhash(lisp_string(&key), json_to_lisp(value), table) };
+            }
+            table
+        }
+    }
+}
+
+// The key of an object named by the symbol NAME. Keywords in plists
+// lose their colon, as with `json-serialize'.
+fn symbol_key(name: &str, plist: bool) -> &str {
+    match name.strip_prefix(':') {
+        Some(stripped) if plist && !stripped.is_empty() => stripped,
+        _ => name,
+    }
+}
+
+fn key_name(key: LispObject, plist: bool) -> Option<String> {
+    if let Some(string) = key.as_string() {
+        return Some(string.to_utf8());
+    }
+    let name = key.as_symbol()?.symbol_name().as_string()?.to_utf8();
+    Some(symbol_key(&name, plist).to_string())
+}
+
+// Read the object OBJECT, a hash table, alist or plist. As with
+// `json-serialize', the first occurrence of a key wins.
+fn object_from_lisp(object: LispObject) -> Option<Map<String, Value>> {
+    let mut entries = Vec::new();
+    if unsafe { HASH_TABLE_P(object) } {
+        let table = unsafe { XHASH_TABLE(object) };
+        for i in 0..unsafe { HASH_TABLE_SIZE(table) } {
+            let key = unsafe { HASH_KEY(table, i) };
+            if !unsafe { hash_unused_entry_key_p(key) } {
+                entries.push((key_name(key, false)?, unsafe { HASH_VALUE(table, i) }));
+            }
+        }
+    } else {
+        let items: Vec<LispObject> = object
+            .iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on)
+            .collect();
+        if items.first().map_or(false, |item| item.is_cons()) {
+            for item in items {
+                let pair = item.as_cons()?;
+                entries.push((key_name(pair.car(), false)?, pair.cdr()));
+            }
+        } else {
+            if items.len() % 2 != 0 {
+                return None;
+            }
+            for pair in items.chunks(2) {
+                entries.push((key_name(pair[0], true)?, pair[1]));
+            }
+        }
+    }
+
+    let mut map = Map::new();
+    for (key, value) in entries {
+        if !map.contains_key(&key) {
+            map.insert(key, json_from_lisp(value)?);
+        }
+    }
+    Some(map)
+}
+
+/// Read OBJECT as a JSON value, or return None if it has no JSON
+/// counterpart.
+pub fn json_from_lisp(object: LispObject) -> Option<Value> {
+    if object.is_nil() {
+        Some(Value::Object(Map::new()))
+    } else if object == QCnull {
+        Some(Value::Null)
+    } else if object == QCfalse {
+        Some(Value::Bool(false))
+    } else if object.is_t() {
+        Some(Value::Bool(true))
+    } else if unsafe { INTEGERP(object) } {
+        let n = unsafe { check_integer_range(object, intmax_t::MIN, intmax_t::MAX) };
+        Some(Value::Number(Number::from(n)))
+    } else if unsafe { FLOATP(object) } {
+        Number::from_f64(unsafe { XFLOAT_DATA(object) }).map(Value::Number)
+    } else if let Some(string) = object.as_string() {
+        Some(Value::String(string.to_utf8()))
+    } else if let Some(vector) = object.as_vector() {
+        vector
+            .as_slice()
+            .iter()
+            .map(|item| json_from_lisp(*item))
+            .collect::<Option<Vec<_>>>()
+            .map(Value::Array)
+    } else if unsafe { HASH_TABLE_P(object) } || object.is_cons() {
+        object_from_lisp(object).map(Value::Object)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plist_keywords_lose_their_colon() {
+        assert_eq!(symbol_key(":name", true), "name");
+        assert_eq!(symbol_key("name", true), "name");
+        assert_eq!(symbol_key(":", true), ":");
+    }
+
+    #[test]
+    fn alist_keys_are_kept() {
+        assert_eq!(symbol_key(":name", false), ":name");
+        assert_eq!(symbol_key("name", false), "name");
+    }
+}
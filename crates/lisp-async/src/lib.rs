@@ -8,7 +8,18 @@ extern crate emacs_sys;
 #[macro_use]
 extern crate lisp_util;
 
+pub mod cancel;
+pub mod data;
+pub mod error;
 pub mod fns;
+pub mod fuzzy;
+pub mod json;
+pub mod pool;
+pub mod process;
+pub mod promise;
+pub mod search;
+pub mod timer;
+pub mod watch;
 
 #[cfg(not(test))]
 include!(concat!(env!("OUT_DIR"), "/c_exports.rs"));
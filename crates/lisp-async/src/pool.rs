@@ -0,0 +1,350 @@
+//! A bounded pool of threads shared by every `rust_worker` stream.
+//! Instead of a thread of its own blocked on its pipe, each stream
+//! queues the messages sent to it, and runs on the pool while it has
+//! some. A stream runs one message at a time, in the order they were
+//! sent, so workers see the same sequence as with a dedicated thread.
+//! A job that panics or fails is reported to the error handler of its
+//! stream, without taking the pool thread down with it.
+
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::LazyLock;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::thread;
+
+use crossbeam::channel::Receiver;
+use crossbeam::channel::Sender;
+
+use emacs_sys::bindings::plist_get;
+use emacs_sys::bindings::plist_put;
+use emacs_sys::bindings::Fprocess_plist;
+use emacs_sys::bindings::Fset_process_plist;
+use emacs_sys::globals::QCbusy;
+use emacs_sys::globals::QCcompleted;
+use emacs_sys::globals::QCmax_threads;
+use emacs_sys::globals::QCqueued;
+use emacs_sys::globals::QCstreams;
+use emacs_sys::globals::QCthreads;
+use emacs_sys::globals::QCworker;
+use emacs_sys::globals::Qclosed;
+use emacs_sys::globals::Qidle;
+use emacs_sys::globals::Qqueued;
+use emacs_sys::globals::Qrunning;
+use emacs_sys::lisp::LispObject;
+use lisp_macros::lisp_fn;
+
+use crate::cancel::CancellationToken;
+use crate::cancel::StreamControl;
+use crate::error::catch_panic;
+use crate::error::WorkerError;
+use crate::fns::EmacsPipe;
+use crate::fns::UserData;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+pub struct WorkerPool {
+    sender: Sender<Task>,
+    receiver: Receiver<Task>,
+    max_threads: AtomicUsize,
+    threads: AtomicUsize,
+    idle: AtomicUsize,
+    busy: AtomicUsize,
+    streams: AtomicUsize,
+    completed: AtomicU64,
+}
+
+static POOL: LazyLock<WorkerPool> = LazyLock::new(|| {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let max_threads = thread::available_parallelism().map_or(4, |n| n.get().clamp(2, 16));
+    WorkerPool {
+        sender,
+        receiver,
+        max_threads: AtomicUsize::new(max_threads),
+        threads: AtomicUsize::new(0),
+        idle: AtomicUsize::new(0),
+        busy: AtomicUsize::new(0),
+        streams: AtomicUsize::new(0),
+        completed: AtomicU64::new(0),
+    }
+});
+
+impl WorkerPool {
+    pub fn global() -> &'static WorkerPool {
+        &POOL
+    }
+
+    /// The number of threads the pool may run at once.
+    pub fn max_threads(&self) -> usize {
+        self.max_threads.load(Ordering::Acquire)
+    }
+
+    /// Run TASK on the pool. Threads are started as tasks come in, up to
+    /// the maximum, and then stay around for later tasks.
+    pub fn execute<F: FnOnce() + Send + 'static>(&'static self, task: F) {
+        // The channel is never closed, as the pool itself holds a receiver.
+        self.sender.send(Box::new(task)).unwrap();
+        if self.idle.load(Ordering::Acquire) == 0 {
+            let threads = self.threads.load(Ordering::Acquire);
+            if threads < self.max_threads.load(Ordering::Acquire)
+                && self
+                    .threads
+                    .compare_exchange(threads, threads + 1, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            {
+                thread::spawn(move || self.run());
+            }
+        }
+    }
+
+    fn run(&self) {
+        loop {
+            self.idle.fetch_add(1, Ordering::AcqRel);
+            let task = self.receiver.recv();
+            self.idle.fetch_sub(1, Ordering::AcqRel);
+            match task {
+                Ok(task) => {
+                    self.busy.fetch_add(1, Ordering::AcqRel);
+                    // Tasks report their own panics where they can, this
+                    // only keeps the thread alive.
+                    if let Err(err) = catch_panic(task) {
+                        eprintln!("Async worker task failed: {}", err);
+                    }
+                    self.busy.fetch_sub(1, Ordering::AcqRel);
+                    self.completed.fetch_add(1, Ordering::AcqRel);
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+struct StreamQueue {
+    messages: VecDeque<usize>,
+    // Set while the stream is queued on the pool or running there.
+    running: bool,
+    // Set while a pool thread runs a message of the stream.
+    processing: bool,
+    closed: bool,
+}
+
+type Job = Box<dyn FnMut(usize, &CancellationToken) -> std::io::Result<()> + Send>;
+
+/// A stream whose messages are processed on the worker pool.
+pub struct PooledStream {
+    queue: Mutex<StreamQueue>,
+    job: Mutex<Job>,
+    control: Arc<StreamControl>,
+    errors: Mutex<(EmacsPipe, Sender<String>)>,
+}
+
+impl PooledStream {
+    /// Make the async stream PROC process its messages on the pool, by
+    /// calling JOB with each of them and its cancellation token. JOB is
+    /// also handed messages cancelled before they started, and should
+    /// check the token before doing any work. An error from JOB ends
+    /// the stream, while a panic is reported to the
+    /// error handler of the stream and the next message processed.
+    pub fn attach<INPUT, F>(proc: LispObject, mut job: F) -> Arc<PooledStream>
+    where
+        INPUT: Send + 'static,
+        F: FnMut(INPUT, &CancellationToken) -> std::io::Result<()> + Send + 'static,
+    {
+        let job: Job = Box::new(move |ptr, token| {
+            let message = unsafe { *Box::from_raw(ptr as *mut INPUT) };
+            job(message, token)
+        });
+        let pipe = unsafe { EmacsPipe::with_process(proc) };
+        let sender = pipe.get_sender();
+        let stream = Arc::new(PooledStream {
+            queue: Mutex::new(StreamQueue {
+                messages: VecDeque::new(),
+                running: false,
+                processing: false,
+                closed: false,
+            }),
+            job: Mutex::new(job),
+            control: StreamControl::attach(proc),
+            errors: Mutex::new((pipe, sender)),
+        });
+
+        unsafe {
+            let plist = Fprocess_plist(proc);
+            let plist = plist_put(plist, QCworker, UserData::new(stream.clone()).into());
+            Fset_process_plist(proc, plist);
+        }
+        POOL.streams.fetch_add(1, Ordering::AcqRel);
+        stream
+    }
+
+    /// Return the pooled stream of PROC, if it has one.
+    pub fn from_process(proc: LispObject) -> Option<Arc<PooledStream>> {
+        let stream = unsafe { plist_get(Fprocess_plist(proc), QCworker) };
+        if stream.is_user_ptr() {
+            Some(unsafe { stream.as_userdata_ref::<Arc<PooledStream>>() }.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Queue the message boxed at PTR. A null PTR closes the stream once
+    /// the messages before it have been processed.
+    pub fn submit(self: &Arc<Self>, ptr: usize) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return;
+        }
+        queue.messages.push_back(ptr);
+        if !queue.running {
+            queue.running = true;
+            let stream = self.clone();
+            POOL.execute(move || stream.run_next());
+        }
+    }
+
+    /// Process the next message, then give the thread back to the pool,
+    /// requeueing the stream if it has more.
+    fn run_next(self: Arc<Self>) {
+        let ptr = {
+            let mut queue = self.queue.lock().unwrap();
+            match queue.messages.pop_front() {
+                Some(ptr) => {
+                    queue.processing = true;
+                    ptr
+                }
+                None => {
+                    queue.running = false;
+                    return;
+                }
+            }
+        };
+
+        let result = if ptr == 0 {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "nullptr",
+            ))
+        } else {
+            // A message cancelled while queued still goes to the job,
+            // which owns it and may have to tell Lisp it is done with it.
+            let token = self.control.next();
+            token.start();
+            // The job mutex is poisoned by a panic, but the job can still
+            // run the messages that follow.
+            let mut job = self.job.lock().unwrap_or_else(PoisonError::into_inner);
+            let result = match catch_panic(|| job(ptr, &token)) {
+                Ok(result) => result,
+                Err(err) => {
+                    self.report(err);
+                    Ok(())
+                }
+            };
+            self.control.finish();
+            result
+        };
+
+        let mut queue = self.queue.lock().unwrap();
+        queue.processing = false;
+        if let Err(err) = result {
+            if err.kind() != std::io::ErrorKind::ConnectionAborted {
+                self.report(WorkerError::Io(err));
+            }
+            queue.closed = true;
+            queue.messages.clear();
+            POOL.streams.fetch_sub(1, Ordering::AcqRel);
+        }
+        if queue.messages.is_empty() {
+            queue.running = false;
+        } else {
+            let stream = self.clone();
+            POOL.execute(move || stream.run_next());
+        }
+    }
+
+    /// Return the state of the stream, `running' while one of its
+    /// messages is processed, `queued' while it waits for a thread of the
+    /// pool, `idle' or `closed', along with the number of messages it has
+    /// yet to process.
+    pub fn state(&self) -> (LispObject, usize) {
+        let queue = self.queue.lock().unwrap();
+        let state = if queue.closed {
+            Qclosed
+        } else if queue.processing {
+            Qrunning
+        } else if queue.running {
+            Qqueued
+        } else {
+            Qidle
+        };
+        (state, queue.messages.len())
+    }
+
+    fn report(&self, error: WorkerError) {
+        let mut errors = self.errors.lock().unwrap_or_else(PoisonError::into_inner);
+        let (pipe, sender) = &mut *errors;
+        // The stream is gone if the error cannot be sent.
+        if let Err(err) = pipe.message_error(sender, error) {
+            crate::fns::eprint_if_unexpected_error(err);
+        }
+    }
+}
+
+/// Return statistics about the thread pool running async workers, as a
+/// plist with the keys :threads, the number of threads started,
+/// :max-threads, :busy, the number of threads running a job, :queued,
+/// the number of jobs waiting for a thread, :streams, the number of
+/// open streams using the pool, and :completed, the number of jobs run
+/// so far.
+#[lisp_fn]
+pub fn async_worker_pool_stats() -> LispObject {
+    let pool = WorkerPool::global();
+    list!(
+        QCthreads,
+        LispObject::from(pool.threads.load(Ordering::Acquire)),
+        QCmax_threads,
+        LispObject::from(pool.max_threads.load(Ordering::Acquire)),
+        QCbusy,
+        LispObject::from(pool.busy.load(Ordering::Acquire)),
+        QCqueued,
+        LispObject::from(pool.receiver.len()),
+        QCstreams,
+        LispObject::from(pool.streams.load(Ordering::Acquire)),
+        QCcompleted,
+        LispObject::from(pool.completed.load(Ordering::Acquire) as usize)
+    )
+}
+
+/// Set the maximum number of threads of the pool running async workers
+/// to SIZE. Threads already started keep running, so lowering the
+/// maximum only takes full effect in a new session.
+#[lisp_fn]
+pub fn async_set_worker_pool_size(size: LispObject) -> LispObject {
+    let size = size.as_natnum_or_error() as usize;
+    if size == 0 {
+        error!("The worker pool needs at least one thread");
+    }
+    WorkerPool::global()
+        .max_threads
+        .store(size, Ordering::Release);
+    LispObject::from(size)
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCbusy, ":busy");
+    def_lisp_sym!(QCcompleted, ":completed");
+    def_lisp_sym!(QCmax_threads, ":max-threads");
+    def_lisp_sym!(QCqueued, ":queued");
+    def_lisp_sym!(QCstreams, ":streams");
+    def_lisp_sym!(QCthreads, ":threads");
+    def_lisp_sym!(QCworker, ":worker");
+    def_lisp_sym!(Qclosed, "closed");
+    def_lisp_sym!(Qidle, "idle");
+    def_lisp_sym!(Qqueued, "queued");
+    def_lisp_sym!(Qrunning, "running");
+}
+
+include!(concat!(env!("OUT_DIR"), "/pool_exports.rs"));
@@ -0,0 +1,339 @@
+//! Subprocesses run off the Lisp thread. Output of the program on stdout
+//! and stderr is streamed to Lisp separately, followed by its exit
+//! status, while input for its stdin is written by a thread of its own.
+
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Child;
+use std::process::ChildStdin;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+use crossbeam::channel::Receiver;
+use crossbeam::channel::Sender;
+
+use emacs_sys::bindings::plist_get;
+use emacs_sys::bindings::plist_put;
+use emacs_sys::bindings::Fdelete_process;
+use emacs_sys::bindings::Fexpand_file_name;
+use emacs_sys::bindings::Ffuncall;
+use emacs_sys::bindings::Fprocess_plist;
+use emacs_sys::bindings::Fset_process_plist;
+use emacs_sys::globals::QCchild;
+use emacs_sys::globals::QCcwd;
+use emacs_sys::globals::QCenv;
+use emacs_sys::globals::QCprocess_handler;
+use emacs_sys::globals::Qasync__process_handler;
+use emacs_sys::globals::Qexit;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qsignal;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::list::LispConsCircularChecks;
+use emacs_sys::list::LispConsEndChecks;
+use emacs_sys::multibyte::LispStringRef;
+use lisp_macros::lisp_fn;
+
+use crate::cancel::CancellationToken;
+use crate::data::lisp_string;
+use crate::data::LispData;
+use crate::error::WorkerError;
+use crate::fns::EmacsPipe;
+use crate::fns::PipeData;
+use crate::fns::UserData;
+use crate::pool::PooledStream;
+
+const READ_BUFFER_SIZE: usize = 8192;
+
+fn event(kind: &str, value: LispData) -> LispData {
+    LispData::List(vec![LispData::Symbol(kind.to_string()), value])
+}
+
+// Stream everything the program writes to 'output' as KIND events.
+// Reads can end in the middle of a UTF-8 sequence, which is kept for the
+// next read rather than being decoded on its own. Once the stream is
+// gone, output is still read and dropped until the program closes it, so
+// that it never blocks on a full pipe and can exit.
+fn forward_output(
+    mut output: impl Read,
+    kind: &str,
+    mut pipe: EmacsPipe,
+    sender: Sender<String>,
+) -> std::io::Result<()> {
+    let mut buffer = [0; READ_BUFFER_SIZE];
+    let mut pending: Vec<u8> = Vec::new();
+    let mut result = Ok(());
+    loop {
+        let n = match output.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if result.is_err() {
+            continue;
+        }
+        pending.extend_from_slice(&buffer[..n]);
+        let valid = match std::str::from_utf8(&pending) {
+            Ok(_) => pending.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => pending.len(),
+        };
+        if valid == 0 {
+            continue;
+        }
+        let text = String::from_utf8_lossy(&pending[..valid]).into_owned();
+        pending.drain(..valid);
+        result = pipe.message_lisp(&sender, event(kind, LispData::String(text)));
+    }
+    if result.is_ok() && !pending.is_empty() {
+        let text = String::from_utf8_lossy(&pending).into_owned();
+        result = pipe.message_lisp(&sender, event(kind, LispData::String(text)));
+    }
+    result
+}
+
+// Queue INPUT for the writer thread. Writes can block for as long as
+// the program doesn't read, so they don't happen here on the pool.
+// Dropping the sender on eof has the writer close stdin.
+fn send_input(input_sender: &mut Option<Sender<Vec<u8>>>, input: LispData) -> std::io::Result<()> {
+    let bytes = match input {
+        LispData::String(s) => s.into_bytes(),
+        LispData::Bytes(bytes) => bytes,
+        LispData::Symbol(s) if s == "eof" => {
+            input_sender.take();
+            return Ok(());
+        }
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Process input must be a string or eof",
+            ))
+        }
+    };
+    // The writer is also gone once one of its writes failed.
+    match input_sender {
+        Some(input_sender) if input_sender.send(bytes).is_ok() => Ok(()),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            "Process input was closed",
+        )),
+    }
+}
+
+// Write everything queued for the program to STDIN, until it is closed
+// with eof or a write fails, which is reported to the error handler.
+fn write_input(
+    mut stdin: ChildStdin,
+    inputs: Receiver<Vec<u8>>,
+    mut pipe: EmacsPipe,
+    sender: Sender<String>,
+) {
+    while let Ok(bytes) = inputs.recv() {
+        if let Err(e) = stdin.write_all(&bytes).and_then(|_| stdin.flush()) {
+            if let Err(e) = pipe.message_error(&sender, WorkerError::Io(e)) {
+                crate::fns::eprint_if_unexpected_error(e);
+            }
+            return;
+        }
+    }
+}
+
+// The pid of a running program, which is cleared before the program is
+// reaped, so that it is never signaled once the pid can be reused.
+type ChildPid = Arc<Mutex<Option<libc::pid_t>>>;
+
+// Wait for CHILD to exit without reaping it, then clear PID and reap it
+// while holding the lock that `async-process-kill' signals under.
+fn wait_child(mut child: Child, pid: &ChildPid) -> std::io::Result<std::process::ExitStatus> {
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let result = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                child.id() as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if result == 0 {
+            break;
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    let mut pid = pid.lock().unwrap();
+    pid.take();
+    child.wait()
+}
+
+fn process_options(command: &mut Command, options: LispObject) {
+    let cwd = unsafe { plist_get(options, QCcwd) };
+    let cwd = if cwd.is_nil() { lisp_string(".") } else { cwd };
+    let cwd: LispStringRef = unsafe { Fexpand_file_name(cwd, Qnil) }.into();
+    command.current_dir(cwd.to_utf8());
+
+    // Entries are VAR=VALUE as in `process-environment', or VAR to
+    // remove VAR from the inherited environment.
+    let env = unsafe { plist_get(options, QCenv) };
+    for entry in env.iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on) {
+        let entry: LispStringRef = entry.into();
+        let entry = entry.to_utf8();
+        match entry.split_once('=') {
+            Some((var, value)) => command.env(var, value),
+            None => command.env_remove(&entry),
+        };
+    }
+}
+
+/// Run PROGRAM with ARGS, a list of strings, without blocking, and
+/// return a stream for it. HANDLER is called with the stream and an
+/// event for everything that happens to the program:
+///
+/// - (stdout TEXT) and (stderr TEXT) for output of the program, decoded
+///   as UTF-8.
+/// - (exit CODE) once it has exited, or (signal NUMBER) if it was
+///   killed by a signal, after all of its output. The stream is then
+///   deleted.
+///
+/// Strings sent to the stream with `async-send-message' are written to
+/// the program's stdin, and sending `eof' closes it. Failures to write
+/// go to the error handler of the stream, see `async-set-error-handler'.
+///
+/// OPTIONS is a plist. `:cwd' is the directory to run PROGRAM in,
+/// `default-directory' if nil. `:env' is a list of strings VAR=VALUE
+/// to add to the environment of Emacs, or VAR to remove it.
+#[lisp_fn(min = "3")]
+pub fn async_start_process(
+    program: LispStringRef,
+    args: LispObject,
+    handler: LispObject,
+    options: LispObject,
+) -> LispObject {
+    let mut command = Command::new(program.to_utf8());
+    for arg in args.iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on) {
+        let arg: LispStringRef = arg.into();
+        command.arg(arg.to_utf8());
+    }
+    process_options(&mut command, options);
+    let mut child = match command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => error!("Failed to start {}: {}", program.to_utf8(), e),
+    };
+
+    let (pipe, proc) = EmacsPipe::with_handler(
+        Qasync__process_handler,
+        LispData::marker(),
+        LispData::marker(),
+    );
+    let pid: ChildPid = Arc::new(Mutex::new(Some(child.id() as libc::pid_t)));
+    unsafe {
+        let plist = Fprocess_plist(proc);
+        let plist = plist_put(plist, QCprocess_handler, handler);
+        let plist = plist_put(plist, QCchild, UserData::new(pid.clone()).into());
+        Fset_process_plist(proc, plist);
+    }
+
+    let sender = pipe.get_sender();
+    let (input_sender, inputs) = crossbeam::channel::unbounded();
+    let mut input_sender = Some(input_sender);
+    let stdin = child.stdin.take().unwrap();
+    let (stdin_pipe, stdin_sender) = (pipe.clone(), sender.clone());
+    thread::spawn(move || write_input(stdin, inputs, stdin_pipe, stdin_sender));
+    PooledStream::attach(proc, move |input: LispData, token: &CancellationToken| {
+        if token.is_cancelled() {
+            return Ok(());
+        }
+        send_input(&mut input_sender, input)
+    });
+
+    // Output is read by threads of its own, as a program can keep it
+    // open for as long as it runs.
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let (stdout_pipe, stdout_sender) = (pipe.clone(), sender.clone());
+    let stdout_reader =
+        thread::spawn(move || forward_output(stdout, "stdout", stdout_pipe, stdout_sender));
+    let mut exit_pipe = pipe;
+    thread::spawn(move || {
+        let _ = forward_output(stderr, "stderr", exit_pipe.clone(), sender.clone());
+        let _ = stdout_reader.join();
+        let status = match wait_child(child, &pid) {
+            Ok(status) => status,
+            Err(e) => {
+                crate::fns::eprint_if_unexpected_error(e);
+                return;
+            }
+        };
+        let status = match (status.code(), status.signal()) {
+            (Some(code), _) => event("exit", LispData::Int(code as i64)),
+            (None, Some(signal)) => event("signal", LispData::Int(signal as i64)),
+            (None, None) => event("exit", LispData::Nil),
+        };
+        if let Err(e) = exit_pipe.message_lisp(&sender, status) {
+            crate::fns::eprint_if_unexpected_error(e);
+        }
+    });
+
+    proc
+}
+
+/// Send SIGNAL, a signal number, to the program run by PROC, a stream
+/// made by `async-start-process'. SIGNAL defaults to SIGTERM. Return
+/// nil if the signal could not be sent, in particular once the program
+/// has exited.
+#[lisp_fn(min = "1")]
+pub fn async_process_kill(proc: LispObject, signal: LispObject) -> bool {
+    let child = unsafe { plist_get(Fprocess_plist(proc), QCchild) };
+    if !child.is_user_ptr() {
+        error!("Not a stream made by async-start-process");
+    }
+    let signal = if signal.is_nil() {
+        libc::SIGTERM
+    } else {
+        signal.as_natnum_or_error() as libc::c_int
+    };
+    let pid = unsafe { child.as_userdata_ref::<ChildPid>() };
+    let pid = pid.lock().unwrap();
+    match *pid {
+        Some(pid) => unsafe { libc::kill(pid, signal) == 0 },
+        None => false,
+    }
+}
+
+/// Hand EVENT of the process stream PROC to its handler, and delete
+/// PROC once the program has exited.
+#[lisp_fn]
+pub fn async__process_handler(proc: LispObject, event: LispObject) -> bool {
+    let handler = unsafe { plist_get(Fprocess_plist(proc), QCprocess_handler) };
+    let mut args = [handler, proc, event];
+    unsafe { Ffuncall(3, args.as_mut_ptr()) };
+    let kind = event.as_cons().map_or(Qnil, |event| event.car());
+    if kind.eq(Qexit) || kind.eq(Qsignal) {
+        unsafe { Fdelete_process(proc) };
+    }
+    true
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCchild, ":child");
+    def_lisp_sym!(QCcwd, ":cwd");
+    def_lisp_sym!(QCenv, ":env");
+    def_lisp_sym!(QCprocess_handler, ":process-handler");
+    def_lisp_sym!(Qasync__process_handler, "async--process-handler");
+    def_lisp_sym!(Qexit, "exit");
+    def_lisp_sym!(Qsignal, "signal");
+}
+
+include!(concat!(env!("OUT_DIR"), "/process_exports.rs"));
@@ -0,0 +1,478 @@
+//! Promises settled by Rust code and consumed from Lisp. A promise is a
+//! record of type `async-promise` holding its state, its value once
+//! settled, and the reactions registered on it by `async-then`,
+//! `async-all` and `async-race`. Promises derived from others are
+//! settled by those reactions on the Lisp thread, so only the ones Rust
+//! settles through a Resolver, from any thread, need a pipe.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use crossbeam::channel::Sender;
+
+use emacs_sys::bindings::internal_condition_case_n;
+use emacs_sys::bindings::make_float;
+use emacs_sys::bindings::plist_get;
+use emacs_sys::bindings::plist_put;
+use emacs_sys::bindings::Faccept_process_output;
+use emacs_sys::bindings::Faref;
+use emacs_sys::bindings::Faset;
+use emacs_sys::bindings::Fdelete_process;
+use emacs_sys::bindings::Ffuncall;
+use emacs_sys::bindings::Fmake_record;
+use emacs_sys::bindings::Fnreverse;
+use emacs_sys::bindings::Fprocess_plist;
+use emacs_sys::bindings::Fset_process_plist;
+use emacs_sys::bindings::Ftype_of;
+use emacs_sys::eval::signal_rust;
+use emacs_sys::globals::QCall;
+use emacs_sys::globals::QCforward;
+use emacs_sys::globals::QCpromise;
+use emacs_sys::globals::QCthen;
+use emacs_sys::globals::Qasync__callback_error;
+use emacs_sys::globals::Qasync__promise_handler;
+use emacs_sys::globals::Qasync_promise;
+use emacs_sys::globals::Qasync_promise_p;
+use emacs_sys::globals::Qerror;
+use emacs_sys::globals::Qfulfilled;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qpending;
+use emacs_sys::globals::Qrejected;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::list::LispConsCircularChecks;
+use emacs_sys::list::LispConsEndChecks;
+use lisp_macros::lisp_fn;
+
+use crate::cancel::duration_from_seconds;
+use crate::data::LispData;
+use crate::error::catch_panic;
+use crate::fns::eprint_if_unexpected_error;
+use crate::fns::make_return_value;
+use crate::fns::to_owned_userdata;
+use crate::fns::EmacsPipe;
+use crate::fns::PipeData;
+use crate::fns::PipeDataOption;
+use crate::fns::UserData;
+use crate::pool::WorkerPool;
+
+// How long 'async-await' waits for process output before checking
+// the promise again, as promises not backed by Rust code are settled
+// by the output of other processes.
+const AWAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The slots of a promise record, after its type.
+const STATE: usize = 1;
+const VALUE: usize = 2;
+const REACTIONS: usize = 3;
+const RESULTS: usize = 4;
+const REMAINING: usize = 5;
+// The pipe of a promise settled by Rust, until it is settled.
+const PIPE: usize = 6;
+const SLOTS: usize = 6;
+
+// The message sent by a Resolver to the Lisp thread. A fulfilled value
+// stays boxed until it is turned into a lisp object by the handler, and
+// an error is a list (CONDITION . DATA).
+enum Settlement {
+    Fulfilled(usize, PipeDataOption),
+    Rejected(LispData),
+}
+
+/// The Rust side of a promise, used to settle it from any thread. A
+/// resolver dropped without settling its promise rejects it, so that
+/// Lisp code awaiting it does not wait forever.
+pub struct Resolver {
+    pipe: EmacsPipe,
+    sender: Sender<String>,
+    settled: bool,
+}
+
+impl Resolver {
+    pub fn resolve<T: PipeData>(mut self, value: T) -> std::io::Result<()> {
+        let ptr = Box::into_raw(Box::new(value)) as usize;
+        self.settle(Settlement::Fulfilled(ptr, T::marker()))
+    }
+
+    /// Reject the promise with an `error` whose message is REASON.
+    pub fn reject(self, reason: String) -> std::io::Result<()> {
+        self.reject_with(error_data(reason))
+    }
+
+    /// Reject the promise with ERROR, a list (CONDITION . DATA) as
+    /// signaled by `signal`.
+    pub fn reject_with(mut self, error: LispData) -> std::io::Result<()> {
+        self.settle(Settlement::Rejected(error))
+    }
+
+    fn settle(&mut self, settlement: Settlement) -> std::io::Result<()> {
+        self.settled = true;
+        self.pipe
+            .message_lisp(&self.sender, UserData::new(settlement))
+    }
+}
+
+impl Drop for Resolver {
+    fn drop(&mut self) {
+        if !self.settled {
+            let reason = "Promise dropped without being settled".to_string();
+            if let Err(err) = self.settle(Settlement::Rejected(error_data(reason))) {
+                eprint_if_unexpected_error(err);
+            }
+        }
+    }
+}
+
+fn error_data(reason: String) -> LispData {
+    LispData::List(vec![
+        LispData::Symbol("error".to_string()),
+        LispData::String(reason),
+    ])
+}
+
+/// Create a pending promise, returning the resolver that settles it
+/// along with the promise to hand to Lisp.
+pub fn promise() -> (Resolver, LispObject) {
+    let promise = make_promise();
+    let (pipe, proc) = EmacsPipe::with_handler(
+        Qasync__promise_handler,
+        UserData::marker(),
+        UserData::marker(),
+    );
+    unsafe {
+        let plist = plist_put(Fprocess_plist(proc), QCpromise, promise);
+        Fset_process_plist(proc, plist);
+    }
+    put(promise, PIPE, proc);
+
+    let sender = pipe.get_sender();
+    let resolver = Resolver {
+        pipe,
+        sender,
+        settled: false,
+    };
+    (resolver, promise)
+}
+
+// Run 'fnc' on the shared worker pool, and return a promise settled
+// with its result. An error is a list (CONDITION . DATA), and the
+// promise is also rejected if 'fnc' panics.
+pub fn rust_promise<OUTPUT, T>(fnc: T) -> LispObject
+where
+    OUTPUT: 'static + Send + PipeData,
+    T: 'static + FnOnce() -> Result<OUTPUT, LispData> + Send,
+{
+    let (resolver, promise) = promise();
+    WorkerPool::global().execute(move || {
+        let result = match catch_panic(fnc) {
+            Ok(Ok(value)) => resolver.resolve(value),
+            Ok(Err(error)) => resolver.reject_with(error),
+            Err(err) => resolver.reject(err.to_string()),
+        };
+        if let Err(err) = result {
+            eprint_if_unexpected_error(err);
+        }
+    });
+    promise
+}
+
+fn make_promise() -> LispObject {
+    let promise = unsafe { Fmake_record(Qasync_promise, LispObject::from(SLOTS), Qnil) };
+    put(promise, STATE, Qpending);
+    promise
+}
+
+fn get(promise: LispObject, slot: usize) -> LispObject {
+    unsafe { Faref(promise, LispObject::from(slot)) }
+}
+
+fn put(promise: LispObject, slot: usize, value: LispObject) {
+    unsafe { Faset(promise, LispObject::from(slot), value) };
+}
+
+fn is_promise(object: LispObject) -> bool {
+    unsafe { Ftype_of(object) }.eq(Qasync_promise)
+}
+
+fn check_promise(object: LispObject) {
+    if !is_promise(object) {
+        wrong_type!(Qasync_promise_p, object);
+    }
+}
+
+// Settle 'promise' unless it already is, and run the reactions
+// registered on it. The pipe of a promise settled by Rust is no longer
+// needed once it is.
+fn settle(promise: LispObject, state: LispObject, value: LispObject) {
+    if get(promise, STATE) != Qpending {
+        return;
+    }
+    put(promise, STATE, state);
+    put(promise, VALUE, value);
+    let reactions = unsafe { Fnreverse(get(promise, REACTIONS)) };
+    put(promise, REACTIONS, Qnil);
+    let pipe = get(promise, PIPE);
+    if pipe.is_not_nil() {
+        put(promise, PIPE, Qnil);
+        unsafe { Fdelete_process(pipe) };
+    }
+
+    for reaction in reactions.iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on) {
+        react(reaction, state, value);
+    }
+}
+
+fn add_reaction(promise: LispObject, reaction: LispObject) {
+    let state = get(promise, STATE);
+    if state == Qpending {
+        let reactions = get(promise, REACTIONS);
+        put(promise, REACTIONS, LispObject::cons(reaction, reactions));
+    } else {
+        react(reaction, state, get(promise, VALUE));
+    }
+}
+
+unsafe extern "C" fn callback_error(
+    err: LispObject,
+    _nargs: libc::ptrdiff_t,
+    _args: *mut LispObject,
+) -> LispObject {
+    LispObject::cons(Qasync__callback_error, err)
+}
+
+// Settle 'derived' with 'result', following it if it is a promise.
+fn resolve_with(derived: LispObject, result: LispObject) {
+    if is_promise(result) {
+        add_reaction(result, list!(QCforward, derived));
+    } else {
+        settle(derived, Qfulfilled, result);
+    }
+}
+
+// A reaction is a list (TAG DERIVED ARGS...), telling how to settle the
+// promise DERIVED once the promise it was registered on settles.
+fn react(reaction: LispObject, state: LispObject, value: LispObject) {
+    let items: Vec<LispObject> = reaction
+        .iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on)
+        .collect();
+    let derived = items[1];
+    match items[0] {
+        QCthen => {
+            let callback = if state == Qfulfilled {
+                items[2]
+            } else {
+                items[3]
+            };
+            if callback.is_nil() {
+                settle(derived, state, value);
+                return;
+            }
+
+            let mut args = [callback, value];
+            let result = unsafe {
+                internal_condition_case_n(
+                    Some(Ffuncall),
+                    args.len() as libc::ptrdiff_t,
+                    args.as_mut_ptr(),
+                    Qerror,
+                    Some(callback_error),
+                )
+            };
+            match result.as_cons() {
+                Some(err) if err.car() == Qasync__callback_error => {
+                    settle(derived, Qrejected, err.cdr())
+                }
+                _ => resolve_with(derived, result),
+            }
+        }
+        QCall => {
+            if get(derived, STATE) != Qpending {
+                return;
+            }
+            if state == Qrejected {
+                settle(derived, Qrejected, value);
+                return;
+            }
+
+            let index = items[2].as_natnum_or_error() as usize;
+            let results = get(derived, RESULTS);
+            if let Some(tail) = results
+                .iter_tails(LispConsEndChecks::on, LispConsCircularChecks::on)
+                .nth(index)
+            {
+                tail.set_car(value);
+            }
+            let remaining = get(derived, REMAINING).as_natnum_or_error() as usize - 1;
+            put(derived, REMAINING, LispObject::from(remaining));
+            if remaining == 0 {
+                settle(derived, Qfulfilled, results);
+            }
+        }
+        _ => settle(derived, state, value),
+    }
+}
+
+/// Internal handler of the pipes of promises settled from Rust.
+#[lisp_fn]
+pub fn async__promise_handler(proc: LispObject, data: LispObject) -> LispObject {
+    let settlement = unsafe { to_owned_userdata(data).unpack::<Settlement>() };
+    let promise = unsafe { plist_get(Fprocess_plist(proc), QCpromise) };
+    match settlement {
+        Settlement::Fulfilled(ptr, option) => {
+            settle(promise, Qfulfilled, make_return_value(ptr, option))
+        }
+        Settlement::Rejected(error) => settle(promise, Qrejected, error.into_lisp()),
+    }
+    Qnil
+}
+
+/// Return t if OBJECT is a promise.
+#[lisp_fn]
+pub fn async_promise_p(object: LispObject) -> bool {
+    is_promise(object)
+}
+
+/// Return the state of PROMISE, one of `pending', `fulfilled' or
+/// `rejected'.
+#[lisp_fn]
+pub fn async_promise_state(promise: LispObject) -> LispObject {
+    check_promise(promise);
+    get(promise, STATE)
+}
+
+/// Wait for PROMISE to settle, and return its value. If PROMISE is
+/// rejected, signal the error it was rejected with.
+///
+/// Process output is accepted while waiting, so timers and the filters
+/// of other processes keep running. If TIMEOUT is non-nil, it is the
+/// number of seconds after which to give up with an error.
+#[lisp_fn(min = "1")]
+pub fn async_await(promise: LispObject, timeout: LispObject) -> LispObject {
+    check_promise(promise);
+    let deadline = if timeout.is_nil() {
+        None
+    } else {
+        Some(Instant::now() + duration_from_seconds(timeout))
+    };
+
+    loop {
+        let state = get(promise, STATE);
+        if state == Qfulfilled {
+            return get(promise, VALUE);
+        }
+        if state == Qrejected {
+            let err = get(promise, VALUE);
+            match err.as_cons() {
+                Some(err) => signal_rust(err.car(), err.cdr()),
+                None => {
+                    xsignal!(Qerror, err);
+                }
+            }
+        }
+
+        let wait = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    error!("Timed out waiting for promise");
+                }
+                (deadline - now).min(AWAIT_POLL_INTERVAL)
+            }
+            None => AWAIT_POLL_INTERVAL,
+        };
+        unsafe { Faccept_process_output(Qnil, make_float(wait.as_secs_f64()), Qnil, Qnil) };
+    }
+}
+
+/// Return a new promise, settled once PROMISE is. When PROMISE is
+/// fulfilled, ON-FULFILLED is called with its value, and when it is
+/// rejected, ON-REJECTED is called with its error, in the form
+/// `condition-case' binds it to. The new promise is fulfilled with what
+/// the callback returns, or follows it if it is a promise itself, and
+/// is rejected if the callback signals an error. A nil callback passes
+/// the outcome of PROMISE on to the new promise.
+///
+/// Callbacks registered on a promise that is already settled are
+/// called right away.
+#[lisp_fn(min = "2")]
+pub fn async_then(
+    promise: LispObject,
+    on_fulfilled: LispObject,
+    on_rejected: LispObject,
+) -> LispObject {
+    check_promise(promise);
+    let derived = make_promise();
+    add_reaction(promise, list!(QCthen, derived, on_fulfilled, on_rejected));
+    derived
+}
+
+/// Return a new promise, settled like PROMISE except that if PROMISE is
+/// rejected, ON-REJECTED is called with its error and the new promise
+/// is settled with the result, as with `async-then'.
+#[lisp_fn]
+pub fn async_catch(promise: LispObject, on_rejected: LispObject) -> LispObject {
+    async_then(promise, Qnil, on_rejected)
+}
+
+/// Return a promise fulfilled with the list of the values of PROMISES
+/// once they are all fulfilled, or rejected with the error of the first
+/// of them to be rejected. Elements of PROMISES that are not promises
+/// are taken as already fulfilled values.
+#[lisp_fn]
+pub fn async_all(promises: LispObject) -> LispObject {
+    let promises: Vec<LispObject> = promises
+        .iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on)
+        .collect();
+    let derived = make_promise();
+    let mut results = Qnil;
+    for _ in 0..promises.len() {
+        results = LispObject::cons(Qnil, results);
+    }
+    put(derived, RESULTS, results);
+    put(derived, REMAINING, LispObject::from(promises.len()));
+    if promises.is_empty() {
+        settle(derived, Qfulfilled, Qnil);
+    }
+
+    for (index, promise) in promises.into_iter().enumerate() {
+        let reaction = list!(QCall, derived, LispObject::from(index));
+        if is_promise(promise) {
+            add_reaction(promise, reaction);
+        } else {
+            react(reaction, Qfulfilled, promise);
+        }
+    }
+    derived
+}
+
+/// Return a promise settled like the first of PROMISES to settle.
+/// Elements of PROMISES that are not promises are taken as already
+/// fulfilled values. If PROMISES is empty, the promise never settles.
+#[lisp_fn]
+pub fn async_race(promises: LispObject) -> LispObject {
+    let derived = make_promise();
+    for promise in promises.iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on) {
+        if is_promise(promise) {
+            add_reaction(promise, list!(QCforward, derived));
+        } else {
+            settle(derived, Qfulfilled, promise);
+        }
+    }
+    derived
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCall, ":all");
+    def_lisp_sym!(QCforward, ":forward");
+    def_lisp_sym!(QCpromise, ":promise");
+    def_lisp_sym!(QCthen, ":then");
+    def_lisp_sym!(Qasync__callback_error, "async--callback-error");
+    def_lisp_sym!(Qasync__promise_handler, "async--promise-handler");
+    def_lisp_sym!(Qasync_promise, "async-promise");
+    def_lisp_sym!(Qasync_promise_p, "async-promise-p");
+    def_lisp_sym!(Qfulfilled, "fulfilled");
+    def_lisp_sym!(Qpending, "pending");
+    def_lisp_sym!(Qrejected, "rejected");
+}
+
+include!(concat!(env!("OUT_DIR"), "/promise_exports.rs"));
@@ -0,0 +1,377 @@
+//! Project-wide regexp search, in the manner of ripgrep. A directory is
+//! walked in parallel, honoring .gitignore and friends, and the matches
+//! found by each walker thread are streamed to Lisp in batches.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use crossbeam::channel::Sender;
+use grep_matcher::Matcher;
+use grep_regex::RegexMatcher;
+use grep_regex::RegexMatcherBuilder;
+use grep_searcher::sinks::Lossy;
+use grep_searcher::BinaryDetection;
+use grep_searcher::Searcher;
+use grep_searcher::SearcherBuilder;
+use ignore::overrides::Override;
+use ignore::overrides::OverrideBuilder;
+use ignore::DirEntry;
+use ignore::ParallelVisitor;
+use ignore::ParallelVisitorBuilder;
+use ignore::WalkBuilder;
+use ignore::WalkState;
+
+use emacs_sys::bindings::plist_get;
+use emacs_sys::bindings::plist_put;
+use emacs_sys::bindings::Fdelete_process;
+use emacs_sys::bindings::Fexpand_file_name;
+use emacs_sys::bindings::Ffuncall;
+use emacs_sys::bindings::Fprocess_plist;
+use emacs_sys::bindings::Fset_process_plist;
+use emacs_sys::globals::QCcase_fold;
+use emacs_sys::globals::QCglob;
+use emacs_sys::globals::QChidden;
+use emacs_sys::globals::QCsearch_handler;
+use emacs_sys::globals::Qasync__search_handler;
+use emacs_sys::globals::Qcancelled;
+use emacs_sys::globals::Qdone;
+use emacs_sys::globals::Qnil;
+use emacs_sys::globals::Qsmart;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::list::LispConsCircularChecks;
+use emacs_sys::list::LispConsEndChecks;
+use emacs_sys::multibyte::LispStringRef;
+use lisp_macros::lisp_fn;
+
+use crate::cancel::CancellationToken;
+use crate::data::LispData;
+use crate::fns::EmacsPipe;
+use crate::fns::PipeData;
+use crate::fns::UserData;
+use crate::pool::PooledStream;
+use crate::pool::WorkerPool;
+
+// The number of matches a walker thread collects before sending them.
+const BATCH_SIZE: usize = 256;
+
+struct SearchRequest {
+    root: PathBuf,
+    matcher: RegexMatcher,
+    hidden: bool,
+    overrides: Option<Override>,
+}
+
+struct SearchVisitor<'s> {
+    matcher: &'s RegexMatcher,
+    searcher: Searcher,
+    token: &'s CancellationToken,
+    pipe: EmacsPipe,
+    sender: Sender<String>,
+    batch: Vec<LispData>,
+}
+
+impl SearchVisitor<'_> {
+    fn search(&mut self, entry: &DirEntry) -> WalkState {
+        let file = entry.path().to_string_lossy().into_owned();
+        let matcher = self.matcher;
+        let token = self.token;
+        let (pipe, sender, batch) = (&mut self.pipe, &self.sender, &mut self.batch);
+        let mut gone = false;
+        let result = self.searcher.search_path(
+            matcher,
+            entry.path(),
+            Lossy(|line_number, line| {
+                // Report the column of the first match on the line in
+                // characters, as Emacs counts them.
+                let start = match matcher.find(line.as_bytes()) {
+                    Ok(Some(m)) => m.start(),
+                    _ => 0,
+                };
+                batch.push(LispData::List(vec![
+                    LispData::String(file.clone()),
+                    LispData::Int(line_number as i64),
+                    LispData::Int(line[..start].chars().count() as i64),
+                    LispData::String(line.trim_end_matches(&['\r', '\n'][..]).to_string()),
+                ]));
+                // Send full batches right away, so that a file with many
+                // matches neither holds them back nor misses a cancel.
+                if batch.len() >= BATCH_SIZE && send_batch(pipe, sender, batch) == WalkState::Quit {
+                    gone = true;
+                }
+                Ok(!gone && !token.is_cancelled())
+            }),
+        );
+        if gone {
+            return WalkState::Quit;
+        }
+        // Unreadable files are skipped, as rg does with a warning.
+        if result.is_err() || self.batch.len() < BATCH_SIZE {
+            return WalkState::Continue;
+        }
+        self.flush()
+    }
+
+    fn flush(&mut self) -> WalkState {
+        send_batch(&mut self.pipe, &self.sender, &mut self.batch)
+    }
+}
+
+fn send_batch(
+    pipe: &mut EmacsPipe,
+    sender: &Sender<String>,
+    batch: &mut Vec<LispData>,
+) -> WalkState {
+    if batch.is_empty() {
+        return WalkState::Continue;
+    }
+    let batch = LispData::List(std::mem::take(batch));
+    match pipe.message_lisp(sender, batch) {
+        Ok(()) => WalkState::Continue,
+        Err(_) => WalkState::Quit,
+    }
+}
+
+impl ParallelVisitor for SearchVisitor<'_> {
+    fn visit(&mut self, entry: Result<DirEntry, ignore::Error>) -> WalkState {
+        if self.token.is_cancelled() {
+            return WalkState::Quit;
+        }
+        match entry {
+            Ok(entry) if entry.file_type().map_or(false, |t| t.is_file()) => self.search(&entry),
+            _ => WalkState::Continue,
+        }
+    }
+}
+
+impl Drop for SearchVisitor<'_> {
+    fn drop(&mut self) {
+        if !self.token.is_cancelled() {
+            self.flush();
+        }
+    }
+}
+
+struct SearchVisitorBuilder<'s> {
+    matcher: &'s RegexMatcher,
+    token: &'s CancellationToken,
+    pipe: &'s EmacsPipe,
+    sender: &'s Sender<String>,
+}
+
+impl<'s> ParallelVisitorBuilder<'s> for SearchVisitorBuilder<'s> {
+    fn build(&mut self) -> Box<dyn ParallelVisitor + 's> {
+        let searcher = SearcherBuilder::new()
+            .line_number(true)
+            .binary_detection(BinaryDetection::quit(b'\x00'))
+            .build();
+        Box::new(SearchVisitor {
+            matcher: self.matcher,
+            searcher,
+            token: self.token,
+            pipe: self.pipe.clone(),
+            sender: self.sender.clone(),
+            batch: Vec::new(),
+        })
+    }
+}
+
+// The number of walker threads running for all searches together.
+static WALKER_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+// Walker threads taken by a search, given back when dropped. The walker
+// brings its own threads, as it needs them all at once to share the
+// directory queue, which pool tasks can't promise. All searches share
+// as many of them as the pool has threads, less the one the first
+// search is holding. A search that finds fewer than two of them free
+// walks on its pool thread alone.
+struct WalkerThreads(usize);
+
+impl WalkerThreads {
+    fn reserve() -> Self {
+        let budget = WorkerPool::global().max_threads().saturating_sub(1);
+        let mut taken = 0;
+        let _ = WALKER_THREADS.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            let free = budget.saturating_sub(used);
+            taken = if free >= 2 { free } else { 0 };
+            Some(used + taken)
+        });
+        WalkerThreads(taken)
+    }
+}
+
+impl Drop for WalkerThreads {
+    fn drop(&mut self) {
+        WALKER_THREADS.fetch_sub(self.0, Ordering::AcqRel);
+    }
+}
+
+fn run_search(
+    request: SearchRequest,
+    token: &CancellationToken,
+    pipe: &mut EmacsPipe,
+    sender: &Sender<String>,
+) -> std::io::Result<()> {
+    if token.is_cancelled() {
+        return pipe.message_lisp(sender, LispData::Symbol("cancelled".to_string()));
+    }
+    let mut walker = WalkBuilder::new(&request.root);
+    walker.hidden(!request.hidden);
+    if let Some(overrides) = request.overrides {
+        walker.overrides(overrides);
+    }
+    let mut visitors = SearchVisitorBuilder {
+        matcher: &request.matcher,
+        token,
+        pipe,
+        sender,
+    };
+    let threads = WalkerThreads::reserve();
+    if threads.0 > 0 {
+        walker.threads(threads.0);
+        walker.build_parallel().visit(&mut visitors);
+    } else {
+        let mut visitor = visitors.build();
+        for entry in walker.build() {
+            if visitor.visit(entry) == WalkState::Quit {
+                break;
+            }
+        }
+    }
+    drop(threads);
+
+    let status = if token.is_cancelled() {
+        "cancelled"
+    } else {
+        "done"
+    };
+    pipe.message_lisp(sender, LispData::Symbol(status.to_string()))
+}
+
+fn glob_overrides(root: &Path, globs: LispObject) -> Option<Override> {
+    if globs.is_nil() {
+        return None;
+    }
+    let globs = if globs.is_string() {
+        list!(globs)
+    } else {
+        globs
+    };
+    let mut overrides = OverrideBuilder::new(root);
+    for glob in globs.iter_cars(LispConsEndChecks::on, LispConsCircularChecks::on) {
+        let glob: LispStringRef = glob.into();
+        if let Err(e) = overrides.add(&glob.to_utf8()) {
+            error!("Invalid glob: {}", e);
+        }
+    }
+    match overrides.build() {
+        Ok(overrides) => Some(overrides),
+        Err(e) => error!("Invalid glob: {}", e),
+    }
+}
+
+fn search_options(options: LispObject) -> (bool, bool, bool, LispObject) {
+    let case_fold = unsafe { plist_get(options, QCcase_fold) };
+    let hidden = unsafe { plist_get(options, QChidden) };
+    let globs = unsafe { plist_get(options, QCglob) };
+    (
+        case_fold.is_not_nil() && !case_fold.eq(Qsmart),
+        case_fold.eq(Qsmart),
+        hidden.is_not_nil(),
+        globs,
+    )
+}
+
+/// Search the files under DIRECTORY for REGEXP, in parallel, calling
+/// HANDLER with the search and a batch of matches as they are found.
+/// REGEXP uses the syntax of Rust regexps, as ripgrep does, rather than
+/// that of Emacs. Files ignored by .gitignore, .ignore and .rgignore
+/// files, as well as hidden and binary files, are skipped.
+///
+/// Each batch is a list of matches (FILE LINE COLUMN TEXT), where LINE
+/// counts from 1, COLUMN is the number of characters before the first
+/// match on the line, and TEXT is the line without its newline. Once
+/// all files are searched, HANDLER is called with `done', or with
+/// `cancelled' if the search was stopped first, and the search is
+/// deleted.
+///
+/// OPTIONS is a plist. `:case-fold' t searches case-insensitively, and
+/// `smart' only does so if REGEXP is all lower case. `:hidden' non-nil
+/// also searches hidden files. `:glob' is a glob or list of globs in
+/// the syntax of rg --glob, restricting the files searched.
+///
+/// Return the search, which `async-cancel' stops.
+#[lisp_fn(min = "3")]
+pub fn async_search(
+    directory: LispObject,
+    regexp: LispStringRef,
+    handler: LispObject,
+    options: LispObject,
+) -> LispObject {
+    let root: LispStringRef = unsafe { Fexpand_file_name(directory, Qnil) }.into();
+    let (case_insensitive, case_smart, hidden, globs) = search_options(options);
+    let matcher = RegexMatcherBuilder::new()
+        .case_insensitive(case_insensitive)
+        .case_smart(case_smart)
+        .build(&regexp.to_utf8());
+    let matcher = match matcher {
+        Ok(matcher) => matcher,
+        Err(e) => error!("Invalid search regexp: {}", e),
+    };
+    let root = PathBuf::from(root.to_utf8());
+    let overrides = glob_overrides(&root, globs);
+    let request = SearchRequest {
+        root,
+        matcher,
+        hidden,
+        overrides,
+    };
+
+    let (mut pipe, proc) = EmacsPipe::with_handler(
+        Qasync__search_handler,
+        UserData::marker(),
+        LispData::marker(),
+    );
+    unsafe {
+        let plist = plist_put(Fprocess_plist(proc), QCsearch_handler, handler);
+        Fset_process_plist(proc, plist);
+    }
+    let mut worker_pipe = pipe.clone();
+    let sender = pipe.get_sender();
+    PooledStream::attach(proc, move |request: UserData, token: &CancellationToken| {
+        let request: SearchRequest = unsafe { request.unpack() };
+        run_search(request, token, &mut worker_pipe, &sender)
+    });
+    if let Err(e) = pipe.message_rust_worker(UserData::new(request)) {
+        unsafe { Fdelete_process(proc) };
+        error!("Failed to start search: {}", e);
+    }
+    proc
+}
+
+/// Hand DATA, a batch of matches of the search PROC, to its handler, and
+/// delete PROC once DATA is `done' or `cancelled'.
+#[lisp_fn]
+pub fn async__search_handler(proc: LispObject, data: LispObject) -> bool {
+    let handler = unsafe { plist_get(Fprocess_plist(proc), QCsearch_handler) };
+    let mut args = [handler, proc, data];
+    unsafe { Ffuncall(3, args.as_mut_ptr()) };
+    if data.eq(Qdone) || data.eq(Qcancelled) {
+        unsafe { Fdelete_process(proc) };
+    }
+    true
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCcase_fold, ":case-fold");
+    def_lisp_sym!(QCglob, ":glob");
+    def_lisp_sym!(QChidden, ":hidden");
+    def_lisp_sym!(QCsearch_handler, ":search-handler");
+    def_lisp_sym!(Qasync__search_handler, "async--search-handler");
+    def_lisp_sym!(Qdone, "done");
+    def_lisp_sym!(Qsmart, "smart");
+}
+
+include!(concat!(env!("OUT_DIR"), "/search_exports.rs"));
@@ -0,0 +1,375 @@
+//! Timers run on the Rust side. A single scheduler thread keeps the
+//! deadlines of every timer, and hands the job of each timer that is
+//! due to the worker pool, whose results reach Lisp through the pipe of
+//! the timer. Unlike `run-with-timer', jobs keep running on time while
+//! Lisp is busy, only the delivery of their results waits for it.
+
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::LazyLock;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use emacs_sys::bindings::plist_get;
+use emacs_sys::bindings::plist_put;
+use emacs_sys::bindings::Fdelete_process;
+use emacs_sys::bindings::Fprocess_plist;
+use emacs_sys::bindings::Fprocessp;
+use emacs_sys::bindings::Fset_process_plist;
+use emacs_sys::bindings::XUSER_PTR;
+use emacs_sys::globals::QCtimer;
+use emacs_sys::globals::Qasync_timer_p;
+use emacs_sys::lisp::LispObject;
+use lisp_macros::lisp_fn;
+
+use crate::cancel::duration_from_seconds;
+use crate::data::LispData;
+use crate::error::catch_panic;
+use crate::fns::EmacsPipe;
+use crate::fns::PipeData;
+use crate::fns::UserData;
+use crate::pool::WorkerPool;
+
+type TimerJob = Box<dyn FnMut() -> std::io::Result<()> + Send>;
+
+struct Timer {
+    interval: Option<Duration>,
+    cancelled: AtomicBool,
+    // Set while the job runs on the pool. A tick that comes around
+    // before the previous one is done is skipped, rather than queued.
+    running: AtomicBool,
+    job: Mutex<TimerJob>,
+}
+
+impl Timer {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    fn fire(self: Arc<Self>) {
+        if self.running.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        WorkerPool::global().execute(move || {
+            if !self.is_cancelled() {
+                let mut job = self.job.lock().unwrap_or_else(PoisonError::into_inner);
+                // The pipe is gone once the timer's process is deleted.
+                if job().is_err() {
+                    self.cancel();
+                }
+            }
+            self.running.store(false, Ordering::Release);
+        });
+    }
+}
+
+struct Scheduled {
+    deadline: Instant,
+    seq: u64,
+    timer: Arc<Timer>,
+}
+
+// BinaryHeap is a max-heap, so the earliest deadline compares greatest.
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+struct Scheduler {
+    queue: Mutex<BinaryHeap<Scheduled>>,
+    condvar: Condvar,
+    seq: AtomicU64,
+}
+
+static SCHEDULER: LazyLock<Scheduler> = LazyLock::new(|| {
+    thread::spawn(|| SCHEDULER.run());
+    Scheduler {
+        queue: Mutex::new(BinaryHeap::new()),
+        condvar: Condvar::new(),
+        seq: AtomicU64::new(0),
+    }
+});
+
+impl Scheduler {
+    fn schedule(&self, deadline: Instant, timer: Arc<Timer>) {
+        let seq = self.seq.fetch_add(1, Ordering::AcqRel);
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        queue.push(Scheduled {
+            deadline,
+            seq,
+            timer,
+        });
+        self.condvar.notify_one();
+    }
+
+    fn run(&self) {
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let now = Instant::now();
+            let deadline = match queue.peek() {
+                Some(next) => next.deadline,
+                None => {
+                    queue = self
+                        .condvar
+                        .wait(queue)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    continue;
+                }
+            };
+            if deadline > now {
+                queue = self
+                    .condvar
+                    .wait_timeout(queue, deadline - now)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+                continue;
+            }
+
+            let Scheduled { timer, .. } = queue.pop().unwrap();
+            if timer.is_cancelled() {
+                continue;
+            }
+            if let Some(interval) = timer.interval {
+                if let Some(next) = next_tick(deadline, interval, now) {
+                    let seq = self.seq.fetch_add(1, Ordering::AcqRel);
+                    queue.push(Scheduled {
+                        deadline: next,
+                        seq,
+                        timer: timer.clone(),
+                    });
+                }
+            }
+            timer.fire();
+        }
+    }
+}
+
+// Keep to the original schedule, unless ticks were missed altogether.
+// A timer whose next tick is beyond what an `Instant' can hold just
+// stops repeating.
+fn next_tick(deadline: Instant, interval: Duration, now: Instant) -> Option<Instant> {
+    deadline
+        .checked_add(interval)
+        .filter(|next| *next > now)
+        .or_else(|| now.checked_add(interval))
+}
+
+extern "C" fn finalize_timer(raw: *mut libc::c_void) {
+    let _timer = unsafe { Box::from_raw(raw as *mut Arc<Timer>) };
+}
+
+// Return the timer of PROC, if PROC is a timer. The finalizer of the
+// user pointer in its plist tells timers apart from other streams.
+fn timer_state(proc: LispObject) -> Option<&'static Arc<Timer>> {
+    if unsafe { Fprocessp(proc) }.is_nil() {
+        return None;
+    }
+    let state = unsafe { plist_get(Fprocess_plist(proc), QCtimer) };
+    if !state.is_user_ptr() {
+        return None;
+    }
+    let finalizer: unsafe extern "C" fn(*mut libc::c_void) = finalize_timer;
+    unsafe {
+        let p = XUSER_PTR(state);
+        match (*p).finalizer {
+            Some(f) if f as usize == finalizer as usize && !(*p).p.is_null() => {
+                Some(&*((*p).p as *const Arc<Timer>))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Create a timer whose process hands the results of 'fnc' to
+// 'handler'. 'fnc' first runs on the worker pool after 'delay', then
+// every 'interval' if there is one, until the timer is cancelled with
+// 'async-timer-cancel'. A tick that returns None sends nothing, and a
+// panic goes to the error handler of the timer. Results are dropped
+// while the channel to Lisp is full, so that a busy Lisp thread never
+// holds up a thread of the pool.
+pub fn rust_timer<OUTPUT, T>(
+    handler: LispObject,
+    delay: Duration,
+    interval: Option<Duration>,
+    mut fnc: T,
+) -> LispObject
+where
+    OUTPUT: 'static + Send + PipeData,
+    T: 'static + FnMut() -> Option<OUTPUT> + Send,
+{
+    let deadline = Instant::now()
+        .checked_add(delay)
+        .filter(|deadline| interval.map_or(true, |i| deadline.checked_add(i).is_some()));
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => {
+            drop(fnc);
+            error!("Timer delay or interval out of range");
+        }
+    };
+    let (mut pipe, proc) = EmacsPipe::with_handler(handler, String::marker(), OUTPUT::marker());
+    let sender = pipe.get_sender();
+    let job: TimerJob = Box::new(move || match catch_panic(&mut fnc) {
+        Ok(Some(output)) => pipe.try_message_lisp(&sender, output).map(|_| ()),
+        Ok(None) => Ok(()),
+        Err(err) => pipe.message_error(&sender, err),
+    });
+    let timer = Arc::new(Timer {
+        interval,
+        cancelled: AtomicBool::new(false),
+        running: AtomicBool::new(false),
+        job: Mutex::new(job),
+    });
+
+    unsafe {
+        let state = Box::into_raw(Box::new(timer.clone()));
+        let state =
+            UserData::with_data_and_finalizer(state as *mut libc::c_void, Some(finalize_timer));
+        let plist = plist_put(Fprocess_plist(proc), QCtimer, state.into());
+        Fset_process_plist(proc, plist);
+    }
+    SCHEDULER.schedule(deadline, timer);
+    proc
+}
+
+/// Call HANDLER with the timer and the number of the tick, counting
+/// from 1, after SECS seconds, and then every REPEAT seconds if REPEAT
+/// is non-nil. The ticks come from the Rust side, so that they keep
+/// their pace while Lisp is busy. Those that pile up meanwhile are
+/// handled in a row once Lisp is free, up to the capacity set with
+/// `async-set-channel-capacity'; further ticks are dropped, leaving a
+/// gap in the numbers. A tick that comes while the previous one is
+/// still being sent is skipped without being counted.
+///
+/// Return the timer, to be stopped with `async-timer-cancel'.
+#[lisp_fn]
+pub fn async_run_with_timer(
+    secs: LispObject,
+    repeat: LispObject,
+    handler: LispObject,
+) -> LispObject {
+    let delay = duration_from_seconds(secs);
+    let interval = if repeat.is_nil() {
+        None
+    } else {
+        Some(duration_from_seconds(repeat))
+    };
+    if interval == Some(Duration::ZERO) {
+        error!("REPEAT must be a positive number of seconds");
+    }
+    let mut tick: i64 = 0;
+    rust_timer(handler, delay, interval, move || {
+        tick += 1;
+        Some(LispData::Int(tick))
+    })
+}
+
+/// Return t if OBJECT is a timer made by `async-run-with-timer' or by
+/// Rust code.
+#[lisp_fn]
+pub fn async_timer_p(object: LispObject) -> bool {
+    timer_state(object).is_some()
+}
+
+/// Stop TIMER, a timer made by `async-run-with-timer' or by Rust code,
+/// and delete its process. A tick running already finishes, but its
+/// result is not delivered.
+#[lisp_fn]
+pub fn async_timer_cancel(timer: LispObject) -> bool {
+    match timer_state(timer) {
+        Some(state) => state.cancel(),
+        None => {
+            wrong_type!(Qasync_timer_p, timer);
+        }
+    }
+    unsafe { Fdelete_process(timer) };
+    true
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCtimer, ":timer");
+    def_lisp_sym!(Qasync_timer_p, "async-timer-p");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduled(deadline: Instant, seq: u64) -> Scheduled {
+        let timer = Arc::new(Timer {
+            interval: None,
+            cancelled: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            job: Mutex::new(Box::new(|| Ok(()))),
+        });
+        Scheduled {
+            deadline,
+            seq,
+            timer,
+        }
+    }
+
+    #[test]
+    fn earliest_deadline_first() {
+        let now = Instant::now();
+        let mut queue = BinaryHeap::new();
+        queue.push(scheduled(now + Duration::from_secs(2), 0));
+        queue.push(scheduled(now, 1));
+        queue.push(scheduled(now + Duration::from_secs(1), 2));
+        queue.push(scheduled(now, 3));
+        let order: Vec<u64> = std::iter::from_fn(|| queue.pop().map(|s| s.seq)).collect();
+        assert_eq!(order, vec![1, 3, 2, 0]);
+    }
+
+    #[test]
+    fn next_tick_keeps_schedule() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(100);
+        let now = start + Duration::from_millis(30);
+        assert_eq!(next_tick(start, interval, now), Some(start + interval));
+    }
+
+    #[test]
+    fn next_tick_after_missed_ticks() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(100);
+        let now = start + Duration::from_millis(250);
+        assert_eq!(next_tick(start, interval, now), Some(now + interval));
+    }
+
+    #[test]
+    fn next_tick_out_of_range() {
+        let now = Instant::now();
+        assert_eq!(next_tick(now, Duration::MAX, now), None);
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/timer_exports.rs"));
@@ -0,0 +1,370 @@
+//! Recursive file watching. The events notify reports for a directory
+//! tree are debounced on a thread of their own, and handed to Lisp in
+//! batches through an async stream.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use notify_debouncer_full::new_debouncer;
+use notify_debouncer_full::notify::event::ModifyKind;
+use notify_debouncer_full::notify::event::RenameMode;
+use notify_debouncer_full::notify::Event;
+use notify_debouncer_full::notify::EventKind;
+use notify_debouncer_full::notify::RecommendedWatcher;
+use notify_debouncer_full::notify::RecursiveMode;
+use notify_debouncer_full::notify::Watcher;
+use notify_debouncer_full::DebounceEventResult;
+use notify_debouncer_full::Debouncer;
+use notify_debouncer_full::FileIdMap;
+
+use emacs_sys::bindings::plist_get;
+use emacs_sys::bindings::plist_put;
+use emacs_sys::bindings::Fdelete_process;
+use emacs_sys::bindings::Fexpand_file_name;
+use emacs_sys::bindings::Fprocess_plist;
+use emacs_sys::bindings::Fset_process_plist;
+use emacs_sys::bindings::Fset_process_sentinel;
+use emacs_sys::bindings::XUSER_PTR;
+use emacs_sys::globals::QCwatch;
+use emacs_sys::globals::Qasync__watch_sentinel;
+use emacs_sys::globals::Qasync_watch_p;
+use emacs_sys::globals::Qerror;
+use emacs_sys::globals::Qnil;
+use emacs_sys::lisp::LispObject;
+use emacs_sys::multibyte::LispStringRef;
+use lisp_macros::lisp_fn;
+
+use crate::cancel::duration_from_seconds;
+use crate::data::lisp_string;
+use crate::data::LispData;
+use crate::fns::to_owned_userdata;
+use crate::fns::EmacsPipe;
+use crate::fns::PipeData;
+use crate::fns::UserData;
+
+const DEFAULT_DELAY: Duration = Duration::from_millis(200);
+
+type FileDebouncer = Debouncer<RecommendedWatcher, FileIdMap>;
+
+fn recursive_mode(recursive: LispObject) -> RecursiveMode {
+    if recursive.is_nil() {
+        RecursiveMode::NonRecursive
+    } else {
+        RecursiveMode::Recursive
+    }
+}
+
+fn expand_path(path: LispObject) -> PathBuf {
+    let expanded: LispStringRef = unsafe { Fexpand_file_name(path, Qnil) }.into();
+    PathBuf::from(expanded.to_utf8())
+}
+
+fn path_data(path: &Path) -> LispData {
+    LispData::String(path.to_string_lossy().into_owned())
+}
+
+// Turn a debounced event into a list (ACTION FILE [FILE1]), using the
+// actions of filenotify.
+fn event_data(event: &Event) -> Option<LispData> {
+    let file = event.paths.first()?;
+    let action = match event.kind {
+        EventKind::Create(_) => "created",
+        EventKind::Remove(_) => "deleted",
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            return Some(LispData::List(vec![
+                LispData::Symbol("renamed".to_string()),
+                path_data(file),
+                path_data(&event.paths[1]),
+            ]));
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => "deleted",
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => "created",
+        // The backend could not tell which side of the rename this is.
+        EventKind::Modify(ModifyKind::Name(_)) => {
+            if file.exists() {
+                "created"
+            } else {
+                "deleted"
+            }
+        }
+        EventKind::Modify(ModifyKind::Metadata(_)) => "attribute-changed",
+        EventKind::Modify(_) => "changed",
+        _ if event.need_rescan() => "rescan",
+        _ => return None,
+    };
+    Some(LispData::List(vec![
+        LispData::Symbol(action.to_string()),
+        path_data(file),
+    ]))
+}
+
+fn watch_debouncer(watch: LispObject) -> LispObject {
+    let debouncer = unsafe { plist_get(Fprocess_plist(watch), QCwatch) };
+    if !debouncer.is_user_ptr() {
+        wrong_type!(Qasync_watch_p, watch);
+    }
+    debouncer
+}
+
+fn with_debouncer<R>(watch: LispObject, f: impl FnOnce(&mut FileDebouncer) -> R) -> R {
+    let debouncer = watch_debouncer(watch);
+    f(unsafe { &mut *((*XUSER_PTR(debouncer)).p as *mut FileDebouncer) })
+}
+
+// Watch PATH, returning the message to signal if that fails, so that
+// the caller can clean up first.
+fn add_path(debouncer: &mut FileDebouncer, path: &Path, mode: RecursiveMode) -> Result<(), String> {
+    if let Err(e) = debouncer.watcher().watch(path, mode) {
+        return Err(format!("Failed to watch {}: {}", path.display(), e));
+    }
+    debouncer.cache().add_root(path, mode);
+    Ok(())
+}
+
+fn signal_watch_error(message: String) -> ! {
+    let message_obj = lisp_string(&message);
+    drop(message);
+    xsignal!(Qerror, message_obj);
+}
+
+/// Watch PATH for changes, calling CALLBACK with the watch and a list
+/// of events once changes have settled for DELAY seconds, 0.2 by
+/// default. If RECURSIVE is non-nil, the whole directory tree under
+/// PATH is watched, including directories created later on.
+///
+/// Each event is a list (ACTION FILE [FILE1]), where ACTION is one of
+/// `created', `deleted', `changed', `attribute-changed' or `renamed',
+/// in which case FILE1 is the new name of FILE. An ACTION of `rescan'
+/// means events were lost and FILE should be scanned again, and
+/// `error' means the watch failed, FILE being the error message.
+///
+/// Return the watch, to be passed to `async-watch-stop' once no longer
+/// needed. Deleting it with `delete-process' stops it as well.
+#[lisp_fn(min = "2")]
+pub fn async_watch(
+    path: LispObject,
+    callback: LispObject,
+    recursive: LispObject,
+    delay: LispObject,
+) -> LispObject {
+    let path = expand_path(path);
+    let delay = if delay.is_nil() {
+        DEFAULT_DELAY
+    } else {
+        duration_from_seconds(delay)
+    };
+
+    // Events are already batched, and the stream must never block the
+    // debouncer, as 'async-watch-stop' waits for it on the lisp thread.
+    let (mut pipe, proc) =
+        EmacsPipe::with_handler_and_capacity(callback, String::marker(), LispData::marker(), None);
+    let sender = pipe.get_sender();
+    let debouncer = new_debouncer(delay, None, move |result: DebounceEventResult| {
+        let events: Vec<LispData> = match result {
+            Ok(events) => events.iter().filter_map(|e| event_data(e)).collect(),
+            Err(errors) => errors
+                .iter()
+                .map(|e| {
+                    LispData::List(vec![
+                        LispData::Symbol("error".to_string()),
+                        LispData::String(e.to_string()),
+                    ])
+                })
+                .collect(),
+        };
+        if !events.is_empty() {
+            let _ = pipe.message_lisp(&sender, LispData::List(events));
+        }
+    });
+    let mut debouncer = match debouncer {
+        Ok(debouncer) => debouncer,
+        Err(e) => {
+            unsafe { Fdelete_process(proc) };
+            let message = format!("Failed to create watch: {}", e);
+            drop(e);
+            drop(path);
+            signal_watch_error(message);
+        }
+    };
+    if let Err(message) = add_path(&mut debouncer, &path, recursive_mode(recursive)) {
+        // Nothing refers to the watch yet, so it goes away before the
+        // error is signaled.
+        debouncer.stop();
+        unsafe { Fdelete_process(proc) };
+        drop(path);
+        signal_watch_error(message);
+    }
+
+    unsafe {
+        let plist = plist_put(
+            Fprocess_plist(proc),
+            QCwatch,
+            UserData::new(debouncer).into(),
+        );
+        Fset_process_plist(proc, plist);
+        Fset_process_sentinel(proc, Qasync__watch_sentinel);
+    }
+    proc
+}
+
+/// Also watch PATH with WATCH, recursively if RECURSIVE is non-nil.
+#[lisp_fn(min = "2")]
+pub fn async_watch_add(watch: LispObject, path: LispObject, recursive: LispObject) -> bool {
+    // The path is only expanded once WATCH is known to be a watch, and
+    // dropped before any error is signaled.
+    let result = with_debouncer(watch, |debouncer| {
+        add_path(debouncer, &expand_path(path), recursive_mode(recursive))
+    });
+    if let Err(message) = result {
+        signal_watch_error(message);
+    }
+    true
+}
+
+/// Stop watching PATH with WATCH.
+#[lisp_fn]
+pub fn async_watch_remove(watch: LispObject, path: LispObject) -> bool {
+    let result = with_debouncer(watch, |debouncer| {
+        let path = expand_path(path);
+        if let Err(e) = debouncer.watcher().unwatch(&path) {
+            return Err(format!("Failed to stop watching {}: {}", path.display(), e));
+        }
+        debouncer.cache().remove_root(&path);
+        Ok(())
+    });
+    if let Err(message) = result {
+        signal_watch_error(message);
+    }
+    true
+}
+
+/// Stop WATCH, a watch created by `async-watch', and delete its
+/// process. No events are delivered afterwards.
+#[lisp_fn]
+pub fn async_watch_stop(watch: LispObject) -> bool {
+    stop_debouncer(watch, watch_debouncer(watch));
+    unsafe { Fdelete_process(watch) };
+    true
+}
+
+// Take DEBOUNCER, the user pointer of WATCH, out of its plist and stop
+// it. This waits for the debouncer thread, so that nothing is written to
+// the pipe once it is closed.
+fn stop_debouncer(watch: LispObject, debouncer: LispObject) {
+    unsafe {
+        let plist = plist_put(Fprocess_plist(watch), QCwatch, Qnil);
+        Fset_process_plist(watch, plist);
+    }
+    let debouncer: FileDebouncer = unsafe { to_owned_userdata(debouncer).unpack() };
+    debouncer.stop();
+}
+
+/// Internal sentinel of watches, stopping the debouncer of WATCH when
+/// it is deleted with `delete-process' rather than `async-watch-stop'.
+#[lisp_fn]
+pub fn async__watch_sentinel(watch: LispObject, _event: LispObject) -> bool {
+    let debouncer = unsafe { plist_get(Fprocess_plist(watch), QCwatch) };
+    if debouncer.is_user_ptr() {
+        stop_debouncer(watch, debouncer);
+    }
+    true
+}
+
+#[allow(dead_code)]
+fn init_syms() {
+    def_lisp_sym!(QCwatch, ":watch");
+    def_lisp_sym!(Qasync_watch_p, "async-watch-p");
+    def_lisp_sym!(Qasync__watch_sentinel, "async--watch-sentinel");
+}
+
+#[cfg(test)]
+mod tests {
+    use notify_debouncer_full::notify::event::AccessKind;
+    use notify_debouncer_full::notify::event::CreateKind;
+    use notify_debouncer_full::notify::event::DataChange;
+    use notify_debouncer_full::notify::event::Flag;
+    use notify_debouncer_full::notify::event::MetadataKind;
+    use notify_debouncer_full::notify::event::RemoveKind;
+
+    use super::*;
+
+    fn event(kind: EventKind, paths: &[&str]) -> Event {
+        paths.iter().fold(Event::new(kind), |event, path| {
+            event.add_path(PathBuf::from(path))
+        })
+    }
+
+    fn data(action: &str, paths: &[&str]) -> Option<LispData> {
+        let mut items = vec![LispData::Symbol(action.to_string())];
+        items.extend(paths.iter().map(|path| LispData::String(path.to_string())));
+        Some(LispData::List(items))
+    }
+
+    #[test]
+    fn actions() {
+        let cases = [
+            (EventKind::Create(CreateKind::File), "created"),
+            (EventKind::Remove(RemoveKind::Folder), "deleted"),
+            (
+                EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+                "changed",
+            ),
+            (
+                EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+                "attribute-changed",
+            ),
+            (
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+                "deleted",
+            ),
+            (
+                EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+                "created",
+            ),
+        ];
+        for (kind, action) in cases {
+            assert_eq!(event_data(&event(kind, &["/a"])), data(action, &["/a"]));
+        }
+    }
+
+    #[test]
+    fn rename() {
+        let kind = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        assert_eq!(
+            event_data(&event(kind, &["/a", "/b"])),
+            data("renamed", &["/a", "/b"])
+        );
+    }
+
+    #[test]
+    fn unknown_rename_side() {
+        let kind = EventKind::Modify(ModifyKind::Name(RenameMode::Any));
+        let missing = "/nonexistent/watch/test/file";
+        assert_eq!(
+            event_data(&event(kind, &[missing])),
+            data("deleted", &[missing])
+        );
+        let dir = std::env::temp_dir();
+        let dir = dir.to_str().unwrap();
+        assert_eq!(event_data(&event(kind, &[dir])), data("created", &[dir]));
+    }
+
+    #[test]
+    fn rescan() {
+        let event = event(EventKind::Other, &["/a"]).set_flag(Flag::Rescan);
+        assert_eq!(event_data(&event), data("rescan", &["/a"]));
+    }
+
+    #[test]
+    fn ignored() {
+        let access = EventKind::Access(AccessKind::Read);
+        assert_eq!(event_data(&event(access, &["/a"])), None);
+        assert_eq!(
+            event_data(&event(EventKind::Create(CreateKind::Any), &[])),
+            None
+        );
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/watch_exports.rs"));
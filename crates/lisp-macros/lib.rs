@@ -115,9 +115,17 @@ pub fn lisp_fn(attr_ts: TokenStream, fn_ts: TokenStream) -> TokenStream {
         pub extern "C" fn #fname(#cargs) -> emacs_sys::lisp::LispObject {
             #body
 
-            let ret = #rname(#rargs);
-            #[allow(unreachable_code)]
-            emacs_sys::lisp::LispObject::from(ret)
+            // Unwinding into the C frames that called us is undefined
+            // behavior, so a panic becomes a Lisp error here.
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                let ret = #rname(#rargs);
+                #[allow(unreachable_code)]
+                emacs_sys::lisp::LispObject::from(ret)
+            }));
+            match result {
+                Ok(ret) => ret,
+                Err(payload) => emacs_sys::eval::signal_panic(payload),
+            }
         }
 
     #[no_mangle]
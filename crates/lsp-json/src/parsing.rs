@@ -6,6 +6,7 @@ use std::io::Result;
 use std::process::Child;
 use std::process::Command;
 use std::process::Stdio;
+use std::sync::mpsc;
 use std::thread;
 
 use lsp_server::Message;
@@ -16,10 +17,12 @@ use lsp_server::Response;
 use serde_json::map::Map;
 use serde_json::Value;
 
+use lisp_async::cancel::CancellationToken;
 use lisp_async::fns::to_owned_userdata;
 use lisp_async::fns::EmacsPipe;
 use lisp_async::fns::PipeDataOption;
 use lisp_async::fns::UserData;
+use lisp_async::pool::PooledStream;
 
 use emacs_sys::lisp::LispObject;
 use emacs_sys::list::LispCons;
@@ -680,18 +683,31 @@ pub fn async_create_process(program: String, args: Vec<String>, pipe: EmacsPipe)
         .stdout(Stdio::piped())
         .spawn()?;
 
-    let mut inn = process.stdin;
-    let in_pipe = pipe.clone();
+    // Writes block for as long as the server doesn't read, so they are
+    // left to a thread of their own rather than done on the worker pool,
+    // which only hands the messages over.
+    let (request_sender, requests) = mpsc::channel::<Message>();
+    let stdin = process.stdin.unwrap();
     thread::spawn(move || {
-        let mut stdout_writer = BufWriter::new(inn.as_mut().unwrap());
-        while let Ok(msg) = in_pipe.read_pend_message::<UserData>() {
-            let value: Message = unsafe { msg.unpack() };
-
-            if let Err(_) = value.write(&mut stdout_writer) {
+        let mut stdin_writer = BufWriter::new(stdin);
+        while let Ok(value) = requests.recv() {
+            if value.write(&mut stdin_writer).is_err() {
                 break;
             }
         }
     });
+    PooledStream::attach(
+        pipe.process(),
+        move |msg: UserData, token: &CancellationToken| {
+            let value: Message = unsafe { msg.unpack() };
+            if token.is_cancelled() {
+                return Ok(());
+            }
+            request_sender.send(value).map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Server input was closed")
+            })
+        },
+    );
 
     let mut out = process.stdout;
     let mut out_pipe = pipe.clone();
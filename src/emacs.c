@@ -2471,6 +2471,10 @@ Using an Emacs configured with --with-x-toolkit=lucid does not have this problem
       syms_of_inotify ();
 #endif /* HAVE_INOTIFY */
 
+#ifdef HAVE_LIBGIT
+      syms_of_git ();
+#endif /* HAVE_LIBGIT */
+
 #ifdef HAVE_KQUEUE
       syms_of_kqueue ();
 #endif /* HAVE_KQUEUE */
@@ -5496,6 +5496,11 @@ extern void syms_of_fontset (void);
 extern void syms_of_inotify (void);
 #endif
 
+/* Defined in crates/git */
+#ifdef HAVE_LIBGIT
+extern void syms_of_git (void);
+#endif
+
 /* Defined in kqueue.c */
 #ifdef HAVE_KQUEUE
 extern void globals_of_kqueue (void);